@@ -0,0 +1,130 @@
+use crate::Result;
+use crate::scanner::Manifest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where replaced and deleted destination files are moved (--backup, --backup-dir, --suffix).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Backup {
+    /// Destination root; a relative `dir` is resolved against it
+    pub root: PathBuf,
+    /// Keep backups in this tree (mirroring the destination) instead of next to the file
+    pub dir: Option<PathBuf>,
+    /// Appended to the backup's file name
+    pub suffix: String,
+}
+
+impl Backup {
+    /// Backup location for `path`, which lives under `root`.
+    pub fn path_for(&self, path: &Path) -> Result<PathBuf> {
+        let target = match &self.dir {
+            None => path.to_path_buf(),
+            Some(dir) => {
+                let rel = path.strip_prefix(&self.root).map_err(|_| {
+                    crate::FastSyncError::Config(format!("{:?} is outside the destination {:?}", path, self.root))
+                })?;
+                self.root.join(dir).join(rel)
+            }
+        };
+
+        let mut name = target.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+        name.push(&self.suffix);
+        Ok(target.with_file_name(name))
+    }
+
+    /// Move `path` aside, replacing an older backup. Does nothing if `path` doesn't exist.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        match fs::symlink_metadata(path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+
+        let target = self.path_for(path)?;
+        crate::apply::local::remove_path(&target, None)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(path, &target)?;
+        Ok(())
+    }
+
+    /// Exclude pattern (relative to the destination root) that keeps a backup dir out of
+    /// destination scans, so --delete doesn't remove it and it is never compared.
+    pub fn exclude_pattern(&self) -> Option<String> {
+        match &self.dir {
+            Some(dir) if dir.is_relative() => Some(format!("/{}", dir.to_string_lossy().replace('\\', "/"))),
+            _ => None,
+        }
+    }
+
+    /// Drop backups kept next to their files from a destination manifest, so --delete
+    /// leaves them alone. Only suffixed paths the source doesn't have count as backups;
+    /// the user's own files with the suffix are compared like any other.
+    pub fn drop_from(&self, mut dest: Manifest, source: &Manifest) -> Manifest {
+        if self.dir.is_some() || self.suffix.is_empty() {
+            return dest;
+        }
+        let in_source: HashSet<&str> = source.entries.iter().map(|e| e.path.as_str()).collect();
+        let is_backup = |path: &str| path.ends_with(self.suffix.as_str()) && !in_source.contains(path);
+        // Whatever is inside a backed up directory goes with it
+        dest.entries.retain(|e| {
+            !std::iter::successors(Some(e.path.as_str()), |p| p.rsplit_once('/').map(|(parent, _)| parent))
+                .any(is_backup)
+        });
+        dest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_next_to_file_and_in_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("conf")).unwrap();
+        fs::write(root.join("conf/app.toml"), "v1").unwrap();
+
+        let beside = Backup { root: root.to_path_buf(), dir: None, suffix: "~".into() };
+        beside.save(&root.join("conf/app.toml")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("conf/app.toml~")).unwrap(), "v1");
+        assert!(!root.join("conf/app.toml").exists());
+        assert_eq!(beside.exclude_pattern(), None);
+
+        // A newer backup replaces the older one
+        fs::write(root.join("conf/app.toml"), "v2").unwrap();
+        let in_dir = Backup { root: root.to_path_buf(), dir: Some(".backup".into()), suffix: String::new() };
+        in_dir.save(&root.join("conf/app.toml")).unwrap();
+        fs::write(root.join("conf/app.toml"), "v3").unwrap();
+        in_dir.save(&root.join("conf/app.toml")).unwrap();
+        assert_eq!(fs::read_to_string(root.join(".backup/conf/app.toml")).unwrap(), "v3");
+        assert_eq!(in_dir.exclude_pattern().as_deref(), Some("/.backup"));
+
+        // Missing files are fine, whole directories move too
+        in_dir.save(&root.join("missing.txt")).unwrap();
+        in_dir.save(&root.join("conf")).unwrap();
+        assert!(root.join(".backup/conf/app.toml~").exists());
+    }
+
+    #[test]
+    fn test_drop_backups_the_source_lacks() {
+        let manifest = |paths: &[&str]| Manifest {
+            entries: paths.iter().map(|p| crate::scanner::FileEntry { path: p.to_string(), ..Default::default() }).collect(),
+            ..Default::default()
+        };
+        let source = manifest(&["notes~", "conf", "conf/app.toml"]);
+        let dest = manifest(&["notes~", "conf", "conf/app.toml", "conf/app.toml~", "old~", "old~/a.txt"]);
+
+        let beside = Backup { root: PathBuf::new(), dir: None, suffix: "~".into() };
+        let kept: Vec<_> = beside.drop_from(dest.clone(), &source).entries.into_iter().map(|e| e.path).collect();
+        assert_eq!(kept, ["notes~", "conf", "conf/app.toml"]);
+
+        // A backup dir is excluded from the scan instead
+        let in_dir = Backup { root: PathBuf::new(), dir: Some(".backup".into()), suffix: String::new() };
+        assert_eq!(in_dir.drop_from(dest, &source).entries.len(), 6);
+    }
+}
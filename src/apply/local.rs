@@ -0,0 +1,258 @@
+use crate::Result;
+use crate::apply::backup::Backup;
+use crate::delta::block_level::{compute_signature, apply_ops, DeltaOp, DeltaStats, DeltaStream, FileSignature, ReadSeek, DELTA_CHUNK_SIZE};
+use std::fs::{self, File};
+use crate::util::hash::{Checksum, HashWriter};
+use std::io::{BufReader, BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+
+/// Temp file used while a destination file is being rewritten.
+/// Appends to the full file name so `a.txt` and `a.log` never share a temp file.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".tmp.rrsync");
+    path.with_file_name(name)
+}
+
+/// Create a destination directory, replacing a file that is in the way.
+pub fn create_dir(path: &Path, backup: Option<&Backup>) -> Result<()> {
+    writable_parent(path);
+    // A symlink to a directory is in the way too; files must not end up behind it
+    if fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+        match backup {
+            Some(backup) => backup.save(path)?,
+            None => fs::remove_file(path)?,
+        }
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+/// Create a symlink at `path` pointing to `target`, replacing whatever is there.
+pub fn create_symlink(path: &Path, target: &str, backup: Option<&Backup>) -> Result<()> {
+    remove_path(path, backup)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    writable_parent(path);
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, path)?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file(target, path)?;
+    Ok(())
+}
+
+/// Make `path` another name of the file at `target`, replacing whatever is there.
+pub fn hard_link(target: &Path, path: &Path, backup: Option<&Backup>) -> Result<()> {
+    remove_path(path, backup)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    writable_parent(path);
+    fs::hard_link(target, path)?;
+    Ok(())
+}
+
+/// Replace `dst` atomically: `fill` writes the new content to a temp file next to it,
+/// which is then synced and renamed over `dst`. The temp file is removed on failure.
+/// With `backup`, the old `dst` is moved aside just before the rename.
+pub fn write_file<F>(dst: &Path, backup: Option<&Backup>, fill: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    prepare_target(dst, backup)?;
+    let tmp = temp_path(dst);
+    let result = fill(&tmp).and_then(|_| {
+        File::options().write(true).open(&tmp)?.sync_all()?;
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => {
+            if let Some(backup) = backup {
+                backup.save(dst)?;
+            }
+            fs::rename(&tmp, dst)?;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Copy `src` over `dst` via a temp file + rename. Returns the bytes copied.
+pub fn copy_file(src: &Path, dst: &Path, backup: Option<&Backup>) -> Result<u64> {
+    let mut copied = 0;
+    write_file(dst, backup, |tmp| {
+        // Content only: a copied read-only mode would keep the temp file from being synced
+        copied = std::io::copy(&mut File::open(src)?, &mut File::create(tmp)?)?;
+        Ok(())
+    })?;
+    Ok(copied)
+}
+
+/// Rewrite `dst` so it matches `src`, reusing the blocks `dst` already has.
+/// Both files are streamed; memory use doesn't grow with file size.
+pub fn patch_file(src: &Path, dst: &Path, block_size: usize, backup: Option<&Backup>) -> Result<DeltaStats> {
+    if !dst.is_file() {
+        return copy_file(src, dst, backup).map(DeltaStats::literal);
+    }
+
+    let sig = signature(dst, block_size)?;
+    let src_file = BufReader::new(File::open(src)?);
+    apply_delta_stream(dst, block_size, backup, move |apply| {
+        let mut stream = DeltaStream::new(src_file, sig);
+        loop {
+            let ops = stream.next_chunk(DELTA_CHUNK_SIZE)?;
+            if ops.is_empty() {
+                return Ok(stream.checksum());
+            }
+            apply(&ops)?;
+        }
+    })
+}
+
+/// Signature of the current destination file; empty when it doesn't exist yet.
+pub fn signature(path: &Path, block_size: usize) -> Result<FileSignature> {
+    if !path.is_file() {
+        return Ok(FileSignature { blocks: vec![], block_size, file_size: 0 });
+    }
+    let mut file = BufReader::new(File::open(path)?);
+    Ok(compute_signature(&mut file, block_size)?)
+}
+
+/// Rebuild `dst` from its current content plus a delta that `produce` feeds in chunks
+/// through the callback it is given. `produce` returns the expected BLAKE3 of the new file;
+/// on mismatch `dst` is left untouched and `ChecksumMismatch` is returned.
+pub fn apply_delta_stream<F>(dst: &Path, block_size: usize, backup: Option<&Backup>, produce: F) -> Result<DeltaStats>
+where
+    F: FnOnce(&mut dyn FnMut(&[DeltaOp]) -> Result<()>) -> Result<Checksum>,
+{
+    let mut old_file = File::open(dst).ok();
+    let mut stats = DeltaStats::default();
+    let stats_ref = &mut stats;
+    // Moved into the closure so the old file is closed before the rename (required on Windows).
+    write_file(dst, backup, move |tmp| {
+        let mut output = HashWriter::new(BufWriter::new(File::create(tmp)?));
+        let mut empty_cursor = Cursor::new(Vec::new());
+        let old_reader: &mut dyn ReadSeek = match &mut old_file {
+            Some(f) => f,
+            None => &mut empty_cursor,
+        };
+
+        let expected = produce(&mut |ops| {
+            apply_ops(old_reader, ops, &mut output, block_size)?;
+            stats_ref.add_ops(ops);
+            Ok(())
+        })?;
+        output.flush()?;
+
+        if output.checksum() != expected {
+            return Err(crate::FastSyncError::ChecksumMismatch { path: dst.to_path_buf() });
+        }
+        Ok(())
+    })?;
+    Ok(stats)
+}
+
+/// Remove a destination file or directory tree, or move it aside with `backup`.
+pub fn remove_path(path: &Path, backup: Option<&Backup>) -> Result<()> {
+    writable_parent(path);
+    if let Some(backup) = backup {
+        return backup.save(path);
+    }
+
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if metadata.is_dir() {
+        writable_tree(path);
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// A directory where a file should go (type change) has to go first.
+fn prepare_target(dst: &Path, backup: Option<&Backup>) -> Result<()> {
+    if dst.is_dir() {
+        remove_path(dst, backup)?;
+    }
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    writable_parent(dst);
+    Ok(())
+}
+
+/// Give the owner write permission on the directory `path` lives in if it lacks
+/// it, as entries can't be created, renamed or removed there otherwise. The engine
+/// applies the directory's own mode again once the run is done with it.
+#[cfg(unix)]
+pub fn writable_parent(path: &Path) {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        add_owner_write(parent);
+    }
+}
+
+/// Read-only directories on Windows still take new entries
+#[cfg(not(unix))]
+pub fn writable_parent(_path: &Path) {}
+
+/// Let every directory of a tree about to be removed be emptied.
+#[cfg(unix)]
+fn writable_tree(dir: &Path) {
+    add_owner_write(dir);
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            writable_tree(&entry.path());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn writable_tree(_dir: &Path) {}
+
+#[cfg(unix)]
+fn add_owner_write(dir: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = fs::symlink_metadata(dir) {
+        let mode = metadata.permissions().mode();
+        if metadata.is_dir() && mode & 0o200 == 0 {
+            // Directories not ours to change fail in the operation itself
+            let _ = fs::set_permissions(dir, fs::Permissions::from_mode(mode | 0o200));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_and_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let dst = dir.path().join("sub/dst.bin");
+
+        let original: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &original).unwrap();
+        copy_file(&src, &dst, None).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), original);
+
+        let mut modified = original.clone();
+        modified[10_000..10_010].copy_from_slice(b"CHANGED!!!");
+        modified.extend_from_slice(b"tail");
+        fs::write(&src, &modified).unwrap();
+        patch_file(&src, &dst, 1024, None).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), modified);
+        assert!(!temp_path(&dst).exists());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::Result;
+use crate::scanner::FileEntry;
+use crate::util::xattr::{self, XattrScope, Xattrs};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// What is carried over besides mtime and mode (--owner, --group, --xattrs, --acls).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Preserve {
+    pub owner: bool,
+    pub group: bool,
+    pub xattrs: XattrScope,
+}
+
+/// A user or group: mapped by `name` when the destination knows it, else by `id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Id {
+    pub id: u32,
+    /// None with --numeric-ids, or when the source has no name for the id
+    pub name: Option<String>,
+}
+
+/// Source metadata applied to a destination file once its content is in place.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    /// Modification time (Unix timestamp, seconds)
+    pub mtime: i64,
+    /// Sub-second part of the modification time
+    pub mtime_nsec: u32,
+    /// Unix permission bits; 0 leaves them alone, ignored on Windows
+    pub mode: u32,
+    pub owner: Option<Id>,
+    pub group: Option<Id>,
+    /// Attributes within `xattr_scope` the path ends up with; None leaves them alone
+    pub xattrs: Option<Xattrs>,
+    pub xattr_scope: XattrScope,
+}
+
+impl Metadata {
+    /// What to apply for `entry`, with whatever else `preserve` asks for.
+    pub fn new(entry: &FileEntry, preserve: Preserve) -> Self {
+        Self {
+            mtime: entry.mtime,
+            mtime_nsec: entry.mtime_nsec,
+            mode: entry.mode,
+            owner: preserve.owner.then(|| Id { id: entry.uid, name: entry.user.clone() }),
+            group: preserve.group.then(|| Id { id: entry.gid, name: entry.group.clone() }),
+            xattrs: entry.xattrs.clone().filter(|_| !preserve.xattrs.is_empty()),
+            xattr_scope: preserve.xattrs,
+        }
+    }
+
+    pub fn modified(&self) -> std::time::SystemTime {
+        UNIX_EPOCH + Duration::new(u64::try_from(self.mtime).unwrap_or(0), self.mtime_nsec)
+    }
+
+    /// Set mtime, permissions, ownership and xattrs on `path`. Writing into a
+    /// directory changes its mtime again, so directories come once they are complete.
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        self.set_mtime(path)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if self.owner.is_some() || self.group.is_some() {
+                let uid = self.owner.as_ref().map(|owner| resolve(owner, crate::util::ids::user_id));
+                let gid = self.group.as_ref().map(|group| resolve(group, crate::util::ids::group_id));
+                std::os::unix::fs::chown(path, uid, gid)?;
+            }
+            // After chown, which may clear setuid/setgid bits
+            if self.mode != 0 {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.mode & 0o7777))?;
+            }
+        }
+
+        // Last, as an access ACL also sets the group permission bits
+        if let Some(values) = &self.xattrs {
+            xattr::write(path, self.xattr_scope, values)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Metadata {
+    /// By path, so files that are read-only (to us) need no write handle.
+    #[cfg(unix)]
+    fn set_mtime(&self, path: &Path) -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let times = [
+            // Access time stays as it is
+            libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+            libc::timespec { tv_sec: self.mtime as libc::time_t, tv_nsec: self.mtime_nsec as _ },
+        ];
+        if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Windows needs a handle with write-attribute access, which directories don't give
+    #[cfg(not(unix))]
+    fn set_mtime(&self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            return Ok(());
+        }
+        let file = std::fs::File::options().write(true).open(path)?;
+        file.set_modified(self.modified())?;
+        Ok(())
+    }
+}
+
+/// The destination's id for `id`: by name where known, else the source's number.
+#[cfg(unix)]
+fn resolve(id: &Id, by_name: fn(&str) -> Option<u32>) -> u32 {
+    id.name.as_deref().and_then(by_name).unwrap_or(id.id)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_apply_mtime_mode_and_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "data").unwrap();
+        let current = fs::metadata(&path).unwrap();
+
+        let entry = FileEntry {
+            mtime: 1_600_000_000,
+            mtime_nsec: 123_456_789,
+            mode: 0o100640,
+            uid: current.uid(),
+            gid: current.gid(),
+            // Unknown names fall back to the numeric ids
+            user: Some("no-such-user-fastsync".into()),
+            ..Default::default()
+        };
+        Metadata::new(&entry, Preserve { owner: true, group: true, ..Default::default() }).apply(&path).unwrap();
+
+        let applied = fs::metadata(&path).unwrap();
+        assert_eq!(applied.mtime(), 1_600_000_000);
+        assert_eq!(applied.mtime_nsec(), 123_456_789);
+        assert_eq!(applied.mode() & 0o7777, 0o640);
+        assert_eq!((applied.uid(), applied.gid()), (current.uid(), current.gid()));
+    }
+}
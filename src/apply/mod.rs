@@ -0,0 +1,4 @@
+pub mod local;
+pub mod backup;
+pub mod partial;
+pub mod metadata;
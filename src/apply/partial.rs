@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where interrupted uploads are kept so the next run can resume them (--partial, --partial-dir).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Partial {
+    /// Keep partial files in this directory, resolved against each file's own directory
+    pub dir: Option<PathBuf>,
+}
+
+impl Partial {
+    /// Partial file for `path`.
+    pub fn path_for(&self, path: &Path) -> PathBuf {
+        let name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+        match &self.dir {
+            Some(dir) => path.parent().unwrap_or(Path::new("")).join(dir).join(name),
+            None => {
+                let mut name = name;
+                name.push(".partial.rrsync");
+                path.with_file_name(name)
+            }
+        }
+    }
+
+    /// Remove the partial file for `path`, and its partial dir once empty.
+    pub fn remove(&self, path: &Path) {
+        let partial_path = self.path_for(path);
+        let _ = fs::remove_file(&partial_path);
+        if let (Some(_), Some(parent)) = (&self.dir, partial_path.parent()) {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+
+    /// Exclude pattern that keeps partial files out of destination scans.
+    pub fn exclude_pattern(&self) -> Option<String> {
+        match &self.dir {
+            Some(dir) if dir.is_relative() => Some(dir.to_string_lossy().replace('\\', "/")),
+            Some(_) => None,
+            None => Some("*.partial.rrsync".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_paths() {
+        let beside = Partial { dir: None };
+        assert_eq!(beside.path_for(Path::new("/srv/app/big.iso")), Path::new("/srv/app/big.iso.partial.rrsync"));
+        assert_eq!(beside.exclude_pattern().as_deref(), Some("*.partial.rrsync"));
+
+        let in_dir = Partial { dir: Some(".partial".into()) };
+        assert_eq!(in_dir.path_for(Path::new("/srv/app/img/big.iso")), Path::new("/srv/app/img/.partial/big.iso"));
+        assert_eq!(in_dir.exclude_pattern().as_deref(), Some(".partial"));
+    }
+}
@@ -4,7 +4,7 @@ use std::path::PathBuf;
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// Source path (local directory)
+    /// Source path (local directory or user@host:path)
     #[arg(value_name = "SOURCE", required_unless_present_any = ["server", "update"])]
     pub source: Option<PathBuf>,
 
@@ -16,10 +16,50 @@ pub struct Args {
     #[arg(short, long)]
     pub exclude: Vec<String>,
 
+    /// Don't skip files listed in .gitignore
+    #[arg(long, default_value_t = false)]
+    pub no_gitignore: bool,
+
     /// Delete extraneous files from destination dirs
     #[arg(long, default_value_t = false)]
     pub delete: bool,
 
+    /// Also delete destination files that match --exclude (implies --delete)
+    #[arg(long, default_value_t = false)]
+    pub delete_excluded: bool,
+
+    /// Abort instead of deleting more than NUM destination files
+    #[arg(long, value_name = "NUM")]
+    pub max_delete: Option<usize>,
+
+    /// Abort instead of deleting more than PERCENT of the destination
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub max_delete_percent: Option<u8>,
+
+    /// Move replaced and deleted destination files aside instead of losing them
+    #[arg(long, default_value_t = false)]
+    pub backup: bool,
+
+    /// Keep backups in DIR (relative to the destination root), mirroring its layout; implies --backup
+    #[arg(long, value_name = "DIR")]
+    pub backup_dir: Option<String>,
+
+    /// Backup suffix (default "~", or none with --backup-dir)
+    #[arg(long, value_name = "SUFFIX")]
+    pub suffix: Option<String>,
+
+    /// Keep interrupted uploads and resume them on the next run
+    #[arg(long, default_value_t = false)]
+    pub partial: bool,
+
+    /// Keep interrupted uploads in DIR (relative to each file's directory); implies --partial
+    #[arg(long, value_name = "DIR")]
+    pub partial_dir: Option<String>,
+
+    /// Don't ask for confirmation before deleting
+    #[arg(short = 'y', long, default_value_t = false)]
+    pub yes: bool,
+
     /// Perform a trial run with no changes made
     #[arg(short = 'n', long, default_value_t = false)]
     pub dry_run: bool,
@@ -32,6 +72,18 @@ pub struct Args {
     #[arg(short = 'z', long, default_value_t = false)]
     pub compress: bool,
 
+    /// zstd level used by --compress in agent mode
+    #[arg(long, default_value_t = 3, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub compress_level: i32,
+
+    /// Retries after a failed connection or transfer
+    #[arg(long, default_value_t = 3, value_name = "NUM")]
+    pub retries: u32,
+
+    /// Seconds before the first retry; doubled for each further one
+    #[arg(long, default_value_t = 1, value_name = "SECS")]
+    pub retry_delay: u64,
+
     /// Number of parallel transfers
     #[arg(short = 'j', long, default_value_t = 4)]
     pub parallel: usize,
@@ -44,6 +96,47 @@ pub struct Args {
     #[arg(short = 'p', long, default_value_t = 22)]
     pub port: u16,
 
+    /// Transfer what symlinks point to instead of the links themselves
+    #[arg(short = 'L', long, default_value_t = false)]
+    pub copy_links: bool,
+
+    /// Skip symlinks that point outside the source tree (absolute or via ..)
+    #[arg(long, default_value_t = false)]
+    pub safe_links: bool,
+
+    /// Preserve hard links between source files
+    #[arg(short = 'H', long, default_value_t = false)]
+    pub hard_links: bool,
+
+    /// Preserve the owner (needs root on the destination); users are mapped by name,
+    /// except over SFTP without the agent
+    #[arg(short = 'o', long, default_value_t = false)]
+    pub owner: bool,
+
+    /// Preserve the group; groups are mapped by name
+    #[arg(short = 'g', long, default_value_t = false)]
+    pub group: bool,
+
+    /// Preserve extended attributes (user.*, security.selinux, ...; Linux, agent mode for remotes)
+    #[arg(short = 'X', long, default_value_t = false)]
+    pub xattrs: bool,
+
+    /// Preserve POSIX ACLs (Linux, agent mode for remotes)
+    #[arg(short = 'A', long, default_value_t = false)]
+    pub acls: bool,
+
+    /// With --owner/--group, keep numeric uid/gid instead of mapping by name
+    #[arg(long, default_value_t = false)]
+    pub numeric_ids: bool,
+
+    /// Print each changed file with why it changed (new, size, mtime, type, perms, owner, deleted, ...)
+    #[arg(long, default_value_t = false)]
+    pub itemize_changes: bool,
+
+    /// Write a JSON report of the run (every action, bytes, duration, errors) to PATH
+    #[arg(long, value_name = "PATH")]
+    pub report_json: Option<PathBuf>,
+
     /// Suppress non-error messages
     #[arg(short, long, default_value_t = false)]
     pub quiet: bool,
@@ -52,10 +145,23 @@ pub struct Args {
     #[arg(short, long, default_value_t = false)]
     pub verbose: bool,
 
+    /// Write agentless uploads straight into the target file instead of a temp
+    /// file renamed over it (saves space for huge files, but isn't atomic)
+    #[arg(long, default_value_t = false)]
+    pub inplace: bool,
+
     /// Enable block-level incremental sync (requires agent on remote)
     #[arg(short = 'b', long, default_value_t = false)]
     pub block_level: bool,
 
+    /// Block size for block-level sync, at most 131072 (default: picked per file from its size)
+    #[arg(long, value_name = "BYTES")]
+    pub block_size: Option<usize>,
+
+    /// Treat mtimes this many seconds apart as equal (e.g. 2 for FAT destinations)
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub modify_window: u64,
+
     /// Skip based on checksum, not mod-time & size
     #[arg(short = 'c', long, default_value_t = false)]
     pub checksum: bool,
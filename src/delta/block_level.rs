@@ -1,10 +1,26 @@
 use crate::delta::rolling::RollingChecksum;
+use crate::util::hash::Checksum;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::{Read, Write, Seek, SeekFrom};
 
 pub const DEFAULT_BLOCK_SIZE: usize = 4096;
 
+/// Bounds for the per-file block size picked by `block_size_for`.
+/// `MAX_BLOCK_SIZE` also caps --block-size and what the agent accepts.
+pub const MIN_BLOCK_SIZE: usize = DEFAULT_BLOCK_SIZE;
+pub const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Max literal bytes batched into one message of a streamed delta
+pub const DELTA_CHUNK_SIZE: usize = 256 * 1024;
+/// Max ops in one message of a streamed delta, which bounds chunks of a file
+/// that is mostly block copies
+pub const DELTA_CHUNK_OPS: usize = 4096;
+
+/// Old-file source for `apply_delta`, usable as a trait object
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
 /// Signature for a single block
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockSignature {
@@ -21,20 +37,67 @@ pub struct FileSignature {
     pub file_size: u64,
 }
 
+impl FileSignature {
+    /// The old file's last block when it is shorter than `block_size`: (index, length)
+    pub fn tail_block(&self) -> Option<(&BlockSignature, usize)> {
+        let last = self.blocks.last()?;
+        let full = (self.blocks.len() as u64 - 1) * self.block_size as u64;
+        let len = self.file_size.checked_sub(full)? as usize;
+        (len > 0 && len < self.block_size).then_some((last, len))
+    }
+}
+
 /// Operation to reconstruct the file
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DeltaOp {
-    /// Copy existing block from old file
-    Copy { index: u32 },
+    /// Copy existing block from old file; `len` is the exact block length
+    /// (shorter than block_size only for the old file's last block)
+    Copy { index: u32, len: u32 },
     /// Insert new data
     Data { data: Vec<u8> },
 }
 
+/// How much of a transferred file was sent as new data and how much was
+/// reused from the old destination file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaStats {
+    pub literal_bytes: u64,
+    pub copied_bytes: u64,
+}
+
+impl DeltaStats {
+    /// A file sent whole.
+    pub fn literal(size: u64) -> Self {
+        Self { literal_bytes: size, copied_bytes: 0 }
+    }
+
+    pub fn add_ops(&mut self, ops: &[DeltaOp]) {
+        for op in ops {
+            match op {
+                DeltaOp::Copy { len, .. } => self.copied_bytes += u64::from(*len),
+                DeltaOp::Data { data } => self.literal_bytes += data.len() as u64,
+            }
+        }
+    }
+}
+
 /// Delta containing ops to patch the file
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileDelta {
     pub ops: Vec<DeltaOp>,
     pub final_size: u64,
+    /// Block size of the signature the delta was computed against
+    pub block_size: usize,
+    /// BLAKE3 of the whole new file, checked after applying
+    pub checksum: Checksum,
+}
+
+/// Pick a block size for a file (rsync heuristic): about sqrt(file_size),
+/// rounded down to a multiple of 8 and clamped to [MIN_BLOCK_SIZE, MAX_BLOCK_SIZE].
+/// Keeps signatures of huge files small without hurting small ones.
+pub fn block_size_for(file_size: u64) -> usize {
+    let sqrt = (file_size as f64).sqrt() as usize;
+    (sqrt & !7).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
 }
 
 /// Compute signature for a file
@@ -45,7 +108,7 @@ pub fn compute_signature<R: Read>(reader: &mut R, block_size: usize) -> std::io:
     let mut file_size = 0;
 
     loop {
-        let n = reader.read(&mut buffer)?;
+        let n = read_block(reader, &mut buffer)?;
         if n == 0 {
             break;
         }
@@ -79,122 +142,297 @@ pub fn compute_signature<R: Read>(reader: &mut R, block_size: usize) -> std::io:
     })
 }
 
+/// Fill `buffer` completely unless EOF comes first, so short reads
+/// (pipes, SFTP) never shift block boundaries.
+fn read_block<R: Read + ?Sized>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 /// Compute delta given a local file and a remote signature
 pub fn compute_delta(local_data: &[u8], remote_sig: &FileSignature) -> FileDelta {
-    let block_size = remote_sig.block_size;
-    let mut ops = Vec::new();
-    
-    // Build lookup table for weak checksums: weak -> Vec<BlockSignature>
-    let mut lookup: HashMap<u32, Vec<&BlockSignature>> = HashMap::new();
-    for block in &remote_sig.blocks {
-        lookup.entry(block.weak).or_default().push(block);
+    let mut stream = DeltaStream::new(local_data, remote_sig.clone());
+    let ops = stream.by_ref()
+        .collect::<std::io::Result<Vec<_>>>()
+        .expect("reading from a slice cannot fail");
+
+    FileDelta {
+        ops,
+        final_size: stream.bytes_read(),
+        block_size: remote_sig.block_size,
+        checksum: stream.checksum(),
     }
+}
+
+/// Streaming delta computation over a `Read`.
+///
+/// Yields `DeltaOp`s as they are found. Pending literal data is flushed every
+/// `block_size` bytes, so the buffer never holds much more than a literal block,
+/// the rolling window and one read-ahead block, whatever the file size.
+pub struct DeltaStream<R: Read> {
+    reader: R,
+    sig: FileSignature,
+    /// weak checksum -> indexes into `sig.blocks`
+    lookup: HashMap<u32, Vec<usize>>,
+    /// buf[lit..pos] is pending literal data, buf[pos..pos + block_size] is the window
+    buf: Vec<u8>,
+    lit: usize,
+    pos: usize,
+    rolling: RollingChecksum,
+    rolling_valid: bool,
+    eof: bool,
+    done: bool,
+    bytes_read: u64,
+    hasher: blake3::Hasher,
+    pending: Vec<DeltaOp>,
+}
+
+impl<R: Read> DeltaStream<R> {
+    pub fn new(reader: R, sig: FileSignature) -> Self {
+        // Build lookup table for weak checksums: weak -> block indexes
+        let mut lookup: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, block) in sig.blocks.iter().enumerate() {
+            lookup.entry(block.weak).or_default().push(i);
+        }
 
-    let mut pos = 0;
-    let mut literal_start = 0;
-    let mut rolling = RollingChecksum::new();
-    
-    // Initialize window
-    if local_data.len() >= block_size {
-        rolling.update(&local_data[0..block_size]);
+        Self {
+            reader,
+            sig,
+            lookup,
+            buf: Vec::new(),
+            lit: 0,
+            pos: 0,
+            rolling: RollingChecksum::new(),
+            rolling_valid: false,
+            eof: false,
+            done: false,
+            bytes_read: 0,
+            hasher: blake3::Hasher::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Total bytes consumed from the reader so far (the final size once exhausted).
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
     }
 
-    while pos + block_size <= local_data.len() {
-        let weak = rolling.digest();
-        let mut matched_block: Option<&BlockSignature> = None;
+    /// BLAKE3 of the input read so far (the whole file once exhausted).
+    pub fn checksum(&self) -> Checksum {
+        *self.hasher.finalize().as_bytes()
+    }
 
-        if let Some(candidates) = lookup.get(&weak) {
-            let chunk = &local_data[pos..pos + block_size];
-            let strong_hash = blake3::hash(chunk);
-            let strong_bytes = &strong_hash.as_bytes()[0..16];
+    /// True once every op has been yielded.
+    pub fn is_finished(&self) -> bool {
+        self.done && self.pending.is_empty()
+    }
 
-            for candidate in candidates {
-                if candidate.strong == strong_bytes {
-                    matched_block = Some(candidate);
-                    break;
+    /// Collect ops until about `max_bytes` of literal data or `DELTA_CHUNK_OPS`
+    /// ops are gathered. Returns an empty chunk once the stream is exhausted.
+    pub fn next_chunk(&mut self, max_bytes: usize) -> std::io::Result<Vec<DeltaOp>> {
+        let mut ops = Vec::new();
+        let mut literal_bytes = 0;
+        while literal_bytes < max_bytes && ops.len() < DELTA_CHUNK_OPS {
+            match self.next() {
+                Some(op) => {
+                    let op = op?;
+                    if let DeltaOp::Data { data } = &op {
+                        literal_bytes += data.len();
+                    }
+                    ops.push(op);
                 }
+                None => break,
             }
         }
+        Ok(ops)
+    }
 
-        if let Some(block) = matched_block {
-            // Found a match!
-            
-            // 1. Flush any pending literal data
-            if pos > literal_start {
-                ops.push(DeltaOp::Data { 
-                    data: local_data[literal_start..pos].to_vec() 
-                });
+    /// Read until `buf` holds at least `want` bytes or the reader is exhausted.
+    fn fill(&mut self, want: usize) -> std::io::Result<()> {
+        let block_size = self.sig.block_size;
+        while self.buf.len() < want && !self.eof {
+            let old_len = self.buf.len();
+            self.buf.resize(old_len + block_size, 0);
+            let n = loop {
+                match self.reader.read(&mut self.buf[old_len..]) {
+                    Ok(n) => break n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        self.buf.truncate(old_len);
+                        return Err(e);
+                    }
+                }
+            };
+            self.buf.truncate(old_len + n);
+            self.hasher.update(&self.buf[old_len..]);
+            if n == 0 {
+                self.eof = true;
             }
+            self.bytes_read += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Drop bytes that have already been emitted.
+    fn compact(&mut self) {
+        if self.lit > 0 {
+            self.buf.drain(..self.lit);
+            self.pos -= self.lit;
+            self.lit = 0;
+        }
+    }
+
+    fn flush_literal(&mut self) {
+        if self.pos > self.lit {
+            self.pending.push(DeltaOp::Data { data: self.buf[self.lit..self.pos].to_vec() });
+            self.lit = self.pos;
+        }
+        self.compact();
+    }
 
-            // 2. Add Copy instruction
-            ops.push(DeltaOp::Copy { index: block.index });
+    fn find_match(&self) -> Option<u32> {
+        let block_size = self.sig.block_size;
+        let candidates = self.lookup.get(&self.rolling.digest())?;
+        let strong_hash = blake3::hash(&self.buf[self.pos..self.pos + block_size]);
+        let strong_bytes = &strong_hash.as_bytes()[0..16];
 
-            // 3. Move window forward by block_size
-            pos += block_size;
-            literal_start = pos;
-            
-            // Reset rolling checksum for next window
-            if pos + block_size <= local_data.len() {
-                rolling.update(&local_data[pos..pos + block_size]);
+        candidates.iter()
+            .map(|&i| &self.sig.blocks[i])
+            .find(|candidate| candidate.strong == strong_bytes)
+            .map(|block| block.index)
+    }
+
+    /// Match the end of the input against the old file's short last block.
+    /// Only called once the input is exhausted.
+    fn match_tail(&self) -> Option<(u32, usize)> {
+        let (tail, len) = self.sig.tail_block()?;
+        let end = self.buf.len();
+        if end < self.lit + len {
+            return None;
+        }
+
+        let chunk = &self.buf[end - len..end];
+        let mut weak_calc = RollingChecksum::new();
+        weak_calc.update(chunk);
+        if weak_calc.digest() != tail.weak {
+            return None;
+        }
+
+        let strong_hash = blake3::hash(chunk);
+        (tail.strong == strong_hash.as_bytes()[0..16]).then_some((tail.index, len))
+    }
+
+    /// Advance until at least one op is pending or the input is exhausted.
+    fn advance(&mut self) -> std::io::Result<()> {
+        let block_size = self.sig.block_size;
+
+        while self.pending.is_empty() && !self.done {
+            self.fill(self.pos + block_size)?;
+
+            if self.buf.len() < self.pos + block_size || block_size == 0 {
+                // Handle remaining data (tail): fewer than block_size bytes are left,
+                // but they may still end with the old file's short last block.
+                match self.match_tail() {
+                    Some((index, len)) => {
+                        self.pos = self.buf.len() - len;
+                        self.flush_literal();
+                        self.pending.push(DeltaOp::Copy { index, len: len as u32 });
+                    }
+                    None => {
+                        self.pos = self.buf.len();
+                        self.flush_literal();
+                    }
+                }
+                self.done = true;
+                break;
             }
-        } else {
-            // No match
-            // Can we roll to the next byte?
-            if pos + block_size < local_data.len() {
-                let old_byte = local_data[pos];
-                let new_byte = local_data[pos + block_size]; 
-                rolling.roll(old_byte, new_byte);
-                pos += 1;
+
+            if !self.rolling_valid {
+                self.rolling.update(&self.buf[self.pos..self.pos + block_size]);
+                self.rolling_valid = true;
+            }
+
+            if let Some(index) = self.find_match() {
+                // Flush pending literal data, then copy the block and jump past it
+                self.flush_literal();
+                self.pending.push(DeltaOp::Copy { index, len: block_size as u32 });
+                self.pos += block_size;
+                self.lit = self.pos;
+                self.rolling_valid = false;
+                self.compact();
+                continue;
+            }
+
+            // No match: roll one byte forward if there is a next byte
+            self.fill(self.pos + block_size + 1)?;
+            if self.buf.len() > self.pos + block_size {
+                let old_byte = self.buf[self.pos];
+                let new_byte = self.buf[self.pos + block_size];
+                self.rolling.roll(old_byte, new_byte);
             } else {
-                // Reached end of file with a full block that didn't match.
-                // We treat the current byte as literal and advance.
-                // Since we can't form a new window, we just break the loop 
-                // (or let the loop condition handle it after increment).
-                pos += 1;
+                // Last full window didn't match; it becomes literal tail data.
+                self.rolling_valid = false;
+            }
+            self.pos += 1;
+
+            if self.pos - self.lit >= block_size {
+                self.flush_literal();
             }
         }
+        Ok(())
     }
+}
 
-    // Handle remaining data (tail)
-    if literal_start < local_data.len() {
-         ops.push(DeltaOp::Data { 
-             data: local_data[literal_start..].to_vec() 
-         });
-    }
+impl<R: Read> Iterator for DeltaStream<R> {
+    type Item = std::io::Result<DeltaOp>;
 
-    FileDelta {
-        ops,
-        final_size: local_data.len() as u64,
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.advance() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(Ok(self.pending.remove(0)))
+        }
     }
 }
 
 /// Apply delta to reconstruct the file
 /// `old_file` must be seekable to read blocks
 pub fn apply_delta<R: Read + Seek + ?Sized, W: Write>(old_file: &mut R, delta: &FileDelta, out_file: &mut W, block_size: usize) -> std::io::Result<()> {
-    for op in &delta.ops {
+    apply_ops(old_file, &delta.ops, out_file, block_size)
+}
+
+/// Apply a run of delta ops, e.g. one chunk of a streamed delta.
+pub fn apply_ops<R: Read + Seek + ?Sized, W: Write>(old_file: &mut R, ops: &[DeltaOp], out_file: &mut W, block_size: usize) -> std::io::Result<()> {
+    for op in ops {
         match op {
             DeltaOp::Data { data } => {
                 out_file.write_all(data)?;
             },
-            DeltaOp::Copy { index } => {
+            DeltaOp::Copy { index, len } => {
                 let offset = *index as u64 * block_size as u64;
                 old_file.seek(SeekFrom::Start(offset))?;
-                
-                // Note: The last block might be smaller than block_size.
-                // But the Signature was generated from the old file, so the index guarantees validity
-                // EXCEPT if the old file was truncated/changed concurrently.
-                // We should read `block_size` or up to EOF.
-                // However, strict rsync usually implies we know the exact size of that block. 
-                // For simplicity here, we assume full blocks except maybe last.
-                // Actually, standard rsync saves block lengths if variable. 
-                // Here we simplify: We just read block_size. 
-                // If it's the last block, read might return less, which is fine if it matches what was signed.
-                // But wait, if we copy the last block of old file, and it was 100 bytes, we copy 100 bytes.
-                // We need to limit the read.
-                
-                let mut buffer = vec![0u8; block_size];
-                let n = old_file.read(&mut buffer)?;
-                out_file.write_all(&buffer[0..n])?;
+
+                // Copy exactly the signed length; a short read means the old file
+                // changed since its signature was taken.
+                let copied = std::io::copy(&mut old_file.take(*len as u64), out_file)?;
+                if copied != *len as u64 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("old file too short for block {} ({} of {} bytes)", index, copied, len),
+                    ));
+                }
             }
         }
     }
@@ -239,12 +477,9 @@ mod tests {
         // Rolling check for "c. E" vs "c.". 
         // If window is 10, and data is only 2, compute_signature produces a block of 2.
         // compute_delta window is 10. It won't match a block of size 2 unless we handle small blocks.
-        // CURRENT SIMPLIFICATION: We only match full blocks. Tail blocks are usually only matched if they are at the end?
-        // Rsync handles this by having a specific size for the last block.
-        // Our `compute_delta` loop condition `pos + block_size <= len` ignores the tail for matching.
-        // So the tail will always be literal data.
-        
-        // Let's verify.
+        // Like rsync, the short last block is only matched at the very end of the new data,
+        // so here "c. EXTRA" stays literal (see test_tail_block_match for the matching case).
+        assert!(!delta.ops.iter().any(|op| matches!(op, DeltaOp::Copy { index: 4, .. })));
         
         // 4. Apply Delta
         let mut old_reader = Cursor::new(old_data);
@@ -253,4 +488,124 @@ mod tests {
         
         assert_eq!(out_buffer, new_data);
     }
+
+    /// Reader that hands out at most 7 bytes per call.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(7).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_tail_block_match() {
+        let old_data = b"Hello world, this is a test file for sync."; // 42 bytes, tail "c."
+        let block_size = 10;
+        let sig = compute_signature(&mut Cursor::new(old_data), block_size).unwrap();
+        assert_eq!(sig.tail_block().map(|(b, len)| (b.index, len)), Some((4, 2)));
+
+        // Unchanged file: every block, including the 2-byte tail, is copied
+        let delta = compute_delta(old_data, &sig);
+        assert!(delta.ops.iter().all(|op| matches!(op, DeltaOp::Copy { .. })));
+        assert!(matches!(delta.ops.last(), Some(DeltaOp::Copy { index: 4, len: 2 })));
+
+        // Edited middle: the tail still matches at the end
+        let new_data = b"Hello world, this is A CHANGED file for sync.";
+        let delta = compute_delta(new_data, &sig);
+        assert!(matches!(delta.ops.last(), Some(DeltaOp::Copy { index: 4, len: 2 })));
+
+        let mut out = Vec::new();
+        apply_delta(&mut Cursor::new(old_data), &delta, &mut out, block_size).unwrap();
+        assert_eq!(out, new_data);
+
+        // File shorter than one block
+        let small = b"tiny";
+        let sig = compute_signature(&mut Cursor::new(small), block_size).unwrap();
+        let delta = compute_delta(small, &sig);
+        assert!(matches!(delta.ops.as_slice(), [DeltaOp::Copy { index: 0, len: 4 }]));
+    }
+
+    #[test]
+    fn test_apply_rejects_truncated_old_file() {
+        let old_data = b"Hello world, this is a test file for sync.";
+        let block_size = 10;
+        let sig = compute_signature(&mut Cursor::new(old_data), block_size).unwrap();
+        let delta = compute_delta(old_data, &sig);
+
+        let mut out = Vec::new();
+        let err = apply_delta(&mut Cursor::new(&old_data[..41]), &delta, &mut out, block_size).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_block_size_for() {
+        assert_eq!(block_size_for(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size_for(1024 * 1024), MIN_BLOCK_SIZE);
+        assert_eq!(block_size_for(100 * 1024 * 1024), 10240);
+        assert_eq!(block_size_for(1 << 40), MAX_BLOCK_SIZE);
+        assert_eq!(block_size_for(10_000_000_000) % 8, 0);
+    }
+
+    #[test]
+    fn test_delta_stream_bounded() {
+        let block_size = 64;
+        let old_data: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 253) as u8).collect();
+        let sig = compute_signature(&mut Trickle(&old_data), block_size).unwrap();
+        assert_eq!(sig.blocks.len(), old_data.len().div_ceil(block_size));
+
+        let mut new_data = b"prefix".to_vec();
+        new_data.extend_from_slice(&old_data[..5_000]);
+        new_data.extend((0..3_000u32).map(|i| (i % 7) as u8));
+        new_data.extend_from_slice(&old_data[5_000..]);
+
+        let mut stream = DeltaStream::new(Trickle(&new_data), sig);
+        let ops: Vec<DeltaOp> = stream.by_ref().collect::<std::io::Result<_>>().unwrap();
+        assert_eq!(stream.bytes_read(), new_data.len() as u64);
+        assert_eq!(stream.checksum(), *blake3::hash(&new_data).as_bytes());
+
+        for op in &ops {
+            if let DeltaOp::Data { data } = op {
+                assert!(data.len() <= block_size);
+            }
+        }
+        assert!(ops.iter().any(|op| matches!(op, DeltaOp::Copy { .. })));
+
+        let mut out = Vec::new();
+        apply_ops(&mut Cursor::new(&old_data), &ops, &mut out, block_size).unwrap();
+        assert_eq!(out, new_data);
+    }
+
+    #[test]
+    fn test_delta_chunks_of_copies_bounded() {
+        let block_size = 16;
+        let data: Vec<u8> = (0..10_000 * block_size as u32).map(|i| (i * 7 % 251) as u8).collect();
+        let sig = compute_signature(&mut Cursor::new(&data), block_size).unwrap();
+
+        // Unchanged: all copies, spread over several chunks of bounded length
+        let mut stream = DeltaStream::new(&data[..], sig);
+        let mut chunks = Vec::new();
+        loop {
+            let ops = stream.next_chunk(DELTA_CHUNK_SIZE).unwrap();
+            if ops.is_empty() {
+                break;
+            }
+            chunks.push(ops);
+        }
+        assert_eq!(chunks.len(), 10_000usize.div_ceil(DELTA_CHUNK_OPS));
+        assert!(chunks.iter().all(|ops| ops.len() <= DELTA_CHUNK_OPS));
+        assert!(stream.is_finished());
+
+        let mut stats = DeltaStats::default();
+        let mut out = Vec::new();
+        for ops in &chunks {
+            stats.add_ops(ops);
+            apply_ops(&mut Cursor::new(&data), ops, &mut out, block_size).unwrap();
+        }
+        assert_eq!(stats, DeltaStats { literal_bytes: 0, copied_bytes: data.len() as u64 });
+        assert_eq!(out, data);
+    }
 }
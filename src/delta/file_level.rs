@@ -1,13 +1,93 @@
 use crate::scanner::{Manifest, FileEntry};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
 
 #[derive(Debug)]
 pub enum SyncAction {
-    Upload(FileEntry),
+    /// Transfer the entry, for the listed reasons
+    Upload(FileEntry, Vec<Change>),
+    /// Make the entry a hard link to the given path, the name of the same file
+    /// whose content is transferred (-H). Runs after every upload.
+    HardLink(FileEntry, String),
+    /// Apply the entry's metadata to the copy already in place, for the listed reasons
+    Metadata(FileEntry, Vec<Change>),
     Delete(String),
 }
 
-pub fn compute_diff(local: &Manifest, remote: &Manifest, delete: bool) -> Vec<SyncAction> {
+/// Why an entry is transferred or removed (--itemize-changes, --report-json).
+/// `Perms`, `Owner`, `Group` and `Xattrs` alone only update the metadata in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    New,
+    /// Kind changed (file, directory, symlink)
+    Type,
+    /// Symlink pointing somewhere else
+    Target,
+    Size,
+    Mtime,
+    Checksum,
+    Perms,
+    Owner,
+    Group,
+    /// Hard-linked to another name of the same file
+    HardLink,
+    /// Extended attributes or ACLs differ
+    Xattrs,
+    Deleted,
+}
+
+impl Change {
+    /// Changes made to the destination copy in place, without transferring content
+    pub fn is_metadata(self) -> bool {
+        matches!(self, Change::Perms | Change::Owner | Change::Group | Change::Xattrs)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Change::New => "new",
+            Change::Type => "type",
+            Change::Target => "target",
+            Change::Size => "size",
+            Change::Mtime => "mtime",
+            Change::Checksum => "checksum",
+            Change::Perms => "perms",
+            Change::Owner => "owner",
+            Change::Group => "group",
+            Change::HardLink => "hardlink",
+            Change::Xattrs => "xattrs",
+            Change::Deleted => "deleted",
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How `compute_diff` decides what to transfer
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Delete remote entries missing locally
+    pub delete: bool,
+    /// Compare content checksums instead of mtime (falls back to mtime when either side has none)
+    pub checksum: bool,
+    /// mtimes this many seconds apart still count as equal (--modify-window)
+    pub modify_window: u64,
+    /// Recreate hard links between source files instead of copying each name (-H)
+    pub hard_links: bool,
+    /// Compare permission bits; off for destinations that have none (Windows)
+    pub perms: bool,
+    /// Compare owners and groups (--owner, --group)
+    pub owner: bool,
+    pub group: bool,
+}
+
+pub fn compute_diff(local: &Manifest, remote: &Manifest, options: &DiffOptions) -> Vec<SyncAction> {
     let mut actions = Vec::new();
     let remote_map: HashMap<&str, &FileEntry> = remote.entries.iter()
         .map(|e| (e.path.as_str(), e))
@@ -16,42 +96,68 @@ pub fn compute_diff(local: &Manifest, remote: &Manifest, delete: bool) -> Vec<Sy
     for local_entry in &local.entries {
         match remote_map.get(local_entry.path.as_str()) {
             Some(remote_entry) => {
-                let needs_update = if local_entry.is_dir != remote_entry.is_dir {
-                    // Type changed (file <-> dir), treat as update (upload will overwrite?)
-                    // If remote is dir and local is file, we might need to remove remote dir first?
-                    // For MVP, just upload. scp handles this?
-                    // If remote is file and local is dir, scp needs recursive?
-                    // Our FileEntry includes directories.
-                    // If it is a directory, size might be 4096.
-                    true
-                } else if local_entry.is_dir {
-                    // Directory exists on both side. Do nothing.
-                    false
-                } else if local_entry.size != remote_entry.size {
-                    true
-                } else if local_entry.mtime > remote_entry.mtime {
-                    true
+                let mut changes = Vec::new();
+                if local_entry.kind != remote_entry.kind {
+                    // Type changed (file, dir, link); the upload replaces what is there
+                    changes.push(Change::Type);
+                } else if local_entry.is_symlink() {
+                    if local_entry.link_target != remote_entry.link_target {
+                        changes.push(Change::Target);
+                    }
+                } else if local_entry.is_file() {
+                    // Directories existing on both sides need nothing
+                    if local_entry.size != remote_entry.size {
+                        changes.push(Change::Size);
+                    }
+                    let content = match (options.checksum, local_entry.checksum, remote_entry.checksum) {
+                        (true, Some(local_sum), Some(remote_sum)) => (local_sum != remote_sum).then_some(Change::Checksum),
+                        _ => is_newer(local_entry, remote_entry, options.modify_window).then_some(Change::Mtime),
+                    };
+                    changes.extend(content);
+                }
+
+                // Links carry no metadata of their own
+                let metadata = if local_entry.is_symlink() || remote_entry.is_symlink() {
+                    Vec::new()
                 } else {
-                    false
+                    metadata_changes(local_entry, remote_entry, options)
                 };
-                
-                if needs_update {
-                    actions.push(SyncAction::Upload(local_entry.clone()));
+                if !changes.is_empty() {
+                    changes.extend(metadata);
+                    actions.push(SyncAction::Upload(local_entry.clone(), changes));
+                } else if !metadata.is_empty() {
+                    actions.push(SyncAction::Metadata(local_entry.clone(), metadata));
                 }
             },
             None => {
-                actions.push(SyncAction::Upload(local_entry.clone()));
+                actions.push(SyncAction::Upload(local_entry.clone(), vec![Change::New]));
             }
         }
     }
     
-    if delete {
+    if options.hard_links {
+        link_hard_links(local, remote, &mut actions);
+    }
+
+    if options.delete {
          let local_map: HashMap<&str, &FileEntry> = local.entries.iter()
             .map(|e| (e.path.as_str(), e))
             .collect();
             
-         for remote_entry in &remote.entries {
-             if !local_map.contains_key(remote_entry.path.as_str()) {
+         let extra: Vec<&FileEntry> = remote.entries.iter()
+             .filter(|e| !local_map.contains_key(e.path.as_str()))
+             .collect();
+
+         // Deleting a directory takes everything below it along; only delete the topmost one
+         let deleted_dirs: HashSet<&str> = extra.iter()
+             .filter(|e| e.is_dir())
+             .map(|e| e.path.as_str())
+             .collect();
+         for remote_entry in extra {
+             let covered = Path::new(&remote_entry.path).ancestors()
+                 .skip(1)
+                 .any(|dir| dir.to_str().is_some_and(|dir| deleted_dirs.contains(dir)));
+             if !covered {
                  actions.push(SyncAction::Delete(remote_entry.path.clone()));
              }
          }
@@ -60,10 +166,110 @@ pub fn compute_diff(local: &Manifest, remote: &Manifest, delete: bool) -> Vec<Sy
     actions
 }
 
+/// Metadata of `local` the destination copy `remote` lacks.
+fn metadata_changes(local: &FileEntry, remote: &FileEntry, options: &DiffOptions) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let (local_perms, remote_perms) = (local.mode & 0o7777, remote.mode & 0o7777);
+    if options.perms && local_perms != 0 && remote_perms != 0 && local_perms != remote_perms {
+        changes.push(Change::Perms);
+    }
+    if options.owner && id_differs((local.uid, &local.user), (remote.uid, &remote.user)) {
+        changes.push(Change::Owner);
+    }
+    if options.group && id_differs((local.gid, &local.group), (remote.gid, &remote.group)) {
+        changes.push(Change::Group);
+    }
+    // Only compared where both scans collected them
+    if matches!((&local.xattrs, &remote.xattrs), (Some(l), Some(r)) if l != r) {
+        changes.push(Change::Xattrs);
+    }
+    changes
+}
+
+/// Owners and groups are mapped by name between machines, so compare names
+/// where both sides have one and ids otherwise.
+fn id_differs(local: (u32, &Option<String>), remote: (u32, &Option<String>)) -> bool {
+    match (local.1, remote.1) {
+        (Some(local_name), Some(remote_name)) => local_name != remote_name,
+        _ => local.0 != remote.0,
+    }
+}
+
+/// Turn uploads of extra names of a multiply-linked file into `HardLink`s to one
+/// name (the smallest path), which alone carries the content. Names already linked
+/// on the destination are left alone unless that content changes.
+fn link_hard_links(local: &Manifest, remote: &Manifest, actions: &mut Vec<SyncAction>) {
+    let mut leaders: HashMap<(u64, u64), &str> = HashMap::new();
+    for entry in &local.entries {
+        if let Some(id) = entry.hard_link_id() {
+            let leader = leaders.entry(id).or_insert(&entry.path);
+            if entry.path.as_str() < *leader {
+                *leader = &entry.path;
+            }
+        }
+    }
+    if leaders.is_empty() {
+        return;
+    }
+
+    let remote_map: HashMap<&str, &FileEntry> = remote.entries.iter()
+        .map(|e| (e.path.as_str(), e))
+        .collect();
+    let uploaded: HashSet<String> = actions.iter()
+        .filter_map(|a| match a { SyncAction::Upload(e, _) => Some(e.path.clone()), _ => None })
+        .collect();
+    // None when the destination doesn't report inodes (SFTP)
+    let linked_on_dest = |a: &str, b: &str| match (remote_map.get(a), remote_map.get(b)) {
+        (Some(a), Some(b)) if a.inode != 0 && b.inode != 0 => Some(a.dev == b.dev && a.inode == b.inode),
+        (Some(_), Some(_)) => None,
+        _ => Some(false),
+    };
+
+    let mut links = Vec::new();
+    for entry in &local.entries {
+        let Some(leader) = entry.hard_link_id().and_then(|id| leaders.get(&id)).filter(|l| **l != entry.path) else {
+            continue;
+        };
+        let relink = uploaded.contains(*leader)
+            || uploaded.contains(&entry.path)
+            || linked_on_dest(&entry.path, leader) == Some(false);
+        if relink {
+            links.push(SyncAction::HardLink(entry.clone(), leader.to_string()));
+        }
+    }
+
+    let linked: HashSet<&str> = links.iter()
+        .filter_map(|a| match a { SyncAction::HardLink(e, _) => Some(e.path.as_str()), _ => None })
+        .collect();
+    actions.retain(|a| !matches!(a, SyncAction::Upload(e, _) | SyncAction::Metadata(e, _) if linked.contains(e.path.as_str())));
+    actions.extend(links);
+}
+
+/// Whether `local` was modified more than `window` seconds after `remote`.
+/// If either side has no sub-second part (SFTP, FAT, coarse filesystems),
+/// both are compared in whole seconds.
+fn is_newer(local: &FileEntry, remote: &FileEntry, window: u64) -> bool {
+    let coarse = local.mtime_nsec == 0 || remote.mtime_nsec == 0;
+    let nanos = |e: &FileEntry| {
+        i128::from(e.mtime) * 1_000_000_000 + if coarse { 0 } else { i128::from(e.mtime_nsec) }
+    };
+    nanos(local) - nanos(remote) > i128::from(window) * 1_000_000_000
+}
+
+/// How many destination entries `deletes` removes, counting everything
+/// inside deleted directories.
+pub fn count_removed(deletes: &[String], dest: &Manifest) -> usize {
+    let deleted: HashSet<&str> = deletes.iter().map(|p| p.as_str()).collect();
+    dest.entries.iter()
+        .filter(|e| Path::new(&e.path).ancestors()
+            .any(|p| p.to_str().is_some_and(|p| deleted.contains(p))))
+        .count()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::scanner::Manifest;
+    use crate::scanner::{FileKind, Manifest};
 
     #[test]
     fn test_compute_diff() {
@@ -71,9 +277,9 @@ mod tests {
             generated_at: 0,
             root_path: ".".into(),
             entries: vec![
-                FileEntry { path: "updated.txt".into(), size: 10, mtime: 100, mode: 0, is_dir: false },
-                FileEntry { path: "new.txt".into(), size: 20, mtime: 200, mode: 0, is_dir: false },
-                FileEntry { path: "same.txt".into(), size: 30, mtime: 300, mode: 0, is_dir: false },
+                FileEntry { path: "updated.txt".into(), size: 10, mtime: 100, mode: 0, ..Default::default() },
+                FileEntry { path: "new.txt".into(), size: 20, mtime: 200, mode: 0, ..Default::default() },
+                FileEntry { path: "same.txt".into(), size: 30, mtime: 300, mode: 0, ..Default::default() },
             ]
         };
         
@@ -81,23 +287,225 @@ mod tests {
             generated_at: 0,
             root_path: ".".into(),
             entries: vec![
-                FileEntry { path: "updated.txt".into(), size: 10, mtime: 90, mode: 0, is_dir: false }, 
-                FileEntry { path: "same.txt".into(), size: 30, mtime: 300, mode: 0, is_dir: false },
-                FileEntry { path: "deleted.txt".into(), size: 40, mtime: 400, mode: 0, is_dir: false },
+                FileEntry { path: "updated.txt".into(), size: 10, mtime: 90, mode: 0, ..Default::default() }, 
+                FileEntry { path: "same.txt".into(), size: 30, mtime: 300, mode: 0, ..Default::default() },
+                FileEntry { path: "deleted.txt".into(), size: 40, mtime: 400, mode: 0, ..Default::default() },
             ]
         };
         
         // Test without delete
-        let actions = compute_diff(&local, &remote, false);
+        let actions = compute_diff(&local, &remote, &DiffOptions::default());
         // updated.txt: local(100) > remote(90) -> Upload
         // new.txt: new -> Upload
         // same.txt: same -> Skip
         // deleted.txt: ignored
         assert_eq!(actions.len(), 2); 
+        assert!(matches!(&actions[0], SyncAction::Upload(e, c) if e.path == "updated.txt" && c == &[Change::Mtime]));
+        assert!(matches!(&actions[1], SyncAction::Upload(e, c) if e.path == "new.txt" && c == &[Change::New]));
         
         // Test with delete
-        let actions = compute_diff(&local, &remote, true);
+        let actions = compute_diff(&local, &remote, &DiffOptions { delete: true, ..Default::default() });
         // + Delete deleted.txt
         assert_eq!(actions.len(), 3);
     }
+
+    #[test]
+    fn test_deletes_collapse_to_topmost_dir() {
+        let entry = |path: &str, is_dir| FileEntry {
+            path: path.into(),
+            kind: if is_dir { FileKind::Dir } else { FileKind::File },
+            ..Default::default()
+        };
+        let manifest = |entries| Manifest { generated_at: 0, root_path: ".".into(), entries };
+
+        let local = manifest(vec![entry("keep", true), entry("keep/a.txt", false)]);
+        let remote = manifest(vec![
+            entry("keep", true),
+            entry("keep/a.txt", false),
+            entry("keep/old.txt", false),
+            entry("gone", true),
+            entry("gone/sub", true),
+            entry("gone/sub/b.txt", false),
+            entry("gone/c.txt", false),
+        ]);
+
+        let actions = compute_diff(&local, &remote, &DiffOptions { delete: true, ..Default::default() });
+        let deleted: Vec<&str> = actions.iter()
+            .filter_map(|a| match a { SyncAction::Delete(p) => Some(p.as_str()), _ => None })
+            .collect();
+        assert_eq!(deleted, vec!["keep/old.txt", "gone"]);
+
+        let deleted: Vec<String> = deleted.into_iter().map(String::from).collect();
+        assert_eq!(count_removed(&deleted, &remote), 5);
+    }
+
+    #[test]
+    fn test_compute_diff_checksum() {
+        let entry = |path: &str, mtime: i64, sum: u8| FileEntry {
+            path: path.into(), size: 10, mtime, checksum: Some([sum; 32]), ..Default::default()
+        };
+        let manifest = |entries| Manifest { generated_at: 0, root_path: ".".into(), entries };
+
+        // mtimes reset by a fresh checkout, content unchanged except "changed.txt"
+        let local = manifest(vec![entry("same.txt", 500, 1), entry("changed.txt", 50, 2)]);
+        let remote = manifest(vec![entry("same.txt", 100, 1), entry("changed.txt", 100, 3)]);
+
+        let actions = compute_diff(&local, &remote, &DiffOptions::default());
+        assert_eq!(actions.len(), 1);
+        assert!(matches!(&actions[0], SyncAction::Upload(e, _) if e.path == "same.txt"));
+
+        let actions = compute_diff(&local, &remote, &DiffOptions { checksum: true, ..Default::default() });
+        assert_eq!(actions.len(), 1);
+        assert!(matches!(&actions[0], SyncAction::Upload(e, c) if e.path == "changed.txt" && c == &[Change::Checksum]));
+    }
+
+    #[test]
+    fn test_compute_diff_subsecond_mtime() {
+        let entry = |path: &str, mtime: i64, mtime_nsec: u32| FileEntry {
+            path: path.into(), size: 10, mtime, mtime_nsec, ..Default::default()
+        };
+        let manifest = |entries| Manifest { generated_at: 0, root_path: ".".into(), entries };
+
+        let local = manifest(vec![
+            entry("same_second.txt", 100, 700_000_000),
+            entry("coarse_remote.txt", 100, 700_000_000),
+            entry("two_seconds.txt", 102, 1),
+        ]);
+        let remote = manifest(vec![
+            entry("same_second.txt", 100, 200_000_000),
+            entry("coarse_remote.txt", 100, 0),
+            entry("two_seconds.txt", 100, 1),
+        ]);
+
+        let uploaded = |options: &DiffOptions| -> Vec<String> {
+            compute_diff(&local, &remote, options).into_iter()
+                .filter_map(|a| match a { SyncAction::Upload(e, _) => Some(e.path), _ => None })
+                .collect()
+        };
+        assert_eq!(uploaded(&DiffOptions::default()), vec!["same_second.txt", "two_seconds.txt"]);
+        assert_eq!(uploaded(&DiffOptions { modify_window: 1, ..Default::default() }), vec!["two_seconds.txt"]);
+        assert!(uploaded(&DiffOptions { modify_window: 2, ..Default::default() }).is_empty());
+    }
+
+    #[test]
+    fn test_compute_diff_xattrs() {
+        let entry = |path: &str, size: u64, label: Option<&str>| FileEntry {
+            path: path.into(),
+            size,
+            mtime: 100,
+            xattrs: label.map(|l| [("user.label".to_string(), l.as_bytes().to_vec())].into()),
+            ..Default::default()
+        };
+        let manifest = |entries| Manifest { generated_at: 0, root_path: ".".into(), entries };
+
+        let local = manifest(vec![
+            entry("relabeled.txt", 10, Some("new")),
+            entry("resized.txt", 20, Some("new")),
+            entry("unscanned.txt", 10, Some("new")),
+            entry("same.txt", 10, Some("same")),
+        ]);
+        let remote = manifest(vec![
+            entry("relabeled.txt", 10, Some("old")),
+            entry("resized.txt", 10, Some("old")),
+            entry("unscanned.txt", 10, None),
+            entry("same.txt", 10, Some("same")),
+        ]);
+
+        let actions = compute_diff(&local, &remote, &DiffOptions::default());
+        assert_eq!(actions.len(), 2);
+        assert!(matches!(&actions[0], SyncAction::Metadata(e, changes)
+            if e.path == "relabeled.txt" && changes == &[Change::Xattrs]));
+        assert!(matches!(&actions[1], SyncAction::Upload(e, changes)
+            if e.path == "resized.txt" && changes == &[Change::Size, Change::Xattrs]));
+    }
+
+    #[test]
+    fn test_compute_diff_perms_and_owner() {
+        let entry = |path: &str, mode: u32, uid: u32, user: Option<&str>| FileEntry {
+            path: path.into(), size: 10, mtime: 100, mode, uid, user: user.map(Into::into), ..Default::default()
+        };
+        let manifest = |entries| Manifest { generated_at: 0, root_path: ".".into(), entries };
+
+        let local = manifest(vec![
+            entry("secret", 0o100600, 1000, None),
+            entry("owned", 0o100644, 1000, Some("deploy")),
+            entry("same_name", 0o100644, 1000, Some("deploy")),
+        ]);
+        let remote = manifest(vec![
+            entry("secret", 0o100644, 1000, None),
+            entry("owned", 0o100644, 1000, Some("www")),
+            // Ids differ between machines, names match
+            entry("same_name", 0o100644, 2000, Some("deploy")),
+        ]);
+
+        let options = DiffOptions { perms: true, owner: true, ..Default::default() };
+        let actions = compute_diff(&local, &remote, &options);
+        assert_eq!(actions.len(), 2);
+        assert!(matches!(&actions[0], SyncAction::Metadata(e, changes)
+            if e.path == "secret" && changes == &[Change::Perms]));
+        assert!(matches!(&actions[1], SyncAction::Metadata(e, changes)
+            if e.path == "owned" && changes == &[Change::Owner]));
+
+        // Without --owner and on destinations without modes, nothing is compared
+        assert!(compute_diff(&local, &remote, &DiffOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_compute_diff_symlinks() {
+        let link = |path: &str, target: &str| FileEntry {
+            path: path.into(), kind: FileKind::Symlink, link_target: Some(target.into()), mtime: 100, ..Default::default()
+        };
+        let file = |path: &str| FileEntry { path: path.into(), mtime: 100, ..Default::default() };
+        let manifest = |entries| Manifest { generated_at: 0, root_path: ".".into(), entries };
+
+        let local = manifest(vec![link("current", "v2"), link("same", "v1"), link("was_file", "v1"), file("was_link")]);
+        let remote = manifest(vec![link("current", "v1"), link("same", "v1"), file("was_file"), link("was_link", "v1")]);
+
+        let actions = compute_diff(&local, &remote, &DiffOptions::default());
+        let changes: Vec<(&str, &[Change])> = actions.iter()
+            .filter_map(|a| match a { SyncAction::Upload(e, c) => Some((e.path.as_str(), c.as_slice())), _ => None })
+            .collect();
+        assert_eq!(changes, vec![
+            ("current", &[Change::Target][..]),
+            ("was_file", &[Change::Type][..]),
+            ("was_link", &[Change::Type][..]),
+        ]);
+    }
+
+    #[test]
+    fn test_compute_diff_hard_links() {
+        let file = |path: &str, inode: u64, mtime: i64| FileEntry {
+            path: path.into(), size: 10, mtime, dev: 1, inode, nlink: 2, ..Default::default()
+        };
+        let manifest = |entries| Manifest { generated_at: 0, root_path: ".".into(), entries };
+        let options = DiffOptions { hard_links: true, ..Default::default() };
+        let summary = |actions: Vec<SyncAction>| -> Vec<String> {
+            actions.into_iter().map(|a| match a {
+                SyncAction::Upload(e, _) => format!("upload {}", e.path),
+                SyncAction::Metadata(e, _) => format!("update {}", e.path),
+                SyncAction::HardLink(e, to) => format!("link {} {}", e.path, to),
+                SyncAction::Delete(p) => format!("delete {}", p),
+            }).collect()
+        };
+
+        // New pair: the content goes once, the other name is linked afterwards
+        let local = manifest(vec![file("b/copy", 7, 100), file("a/orig", 7, 100)]);
+        let empty = manifest(vec![]);
+        assert_eq!(summary(compute_diff(&local, &empty, &options)), vec!["upload a/orig", "link b/copy a/orig"]);
+
+        // Already linked on the destination: nothing to do
+        let linked = manifest(vec![file("a/orig", 3, 100), file("b/copy", 3, 100)]);
+        assert!(compute_diff(&local, &linked, &options).is_empty());
+
+        // Separate copies on the destination get linked
+        let copies = manifest(vec![file("a/orig", 3, 100), file("b/copy", 4, 100)]);
+        assert_eq!(summary(compute_diff(&local, &copies, &options)), vec!["link b/copy a/orig"]);
+
+        // New content: the relinked name follows the re-uploaded one
+        let newer = manifest(vec![file("b/copy", 7, 200), file("a/orig", 7, 200)]);
+        assert_eq!(summary(compute_diff(&newer, &linked, &options)), vec!["upload a/orig", "link b/copy a/orig"]);
+
+        // Without -H every name is a file of its own
+        assert_eq!(compute_diff(&local, &empty, &DiffOptions::default()).len(), 2);
+    }
 }
@@ -13,6 +13,12 @@ pub struct RollingChecksum {
     window_size: usize,
 }
 
+impl Default for RollingChecksum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RollingChecksum {
     pub fn new() -> Self {
         Self { a: 0, b: 0, window_size: 0 }
@@ -1,17 +1,31 @@
 use crate::Result;
 use crate::config::Args;
 use crate::transport::ssh::{SshConfig, SshConnection};
-use crate::transport::Transport;
-use crate::scanner::{Scanner, LocalScanner, Manifest};
-use crate::remote::agentless::AgentlessRemote;
-use crate::remote::agent::AgentRemote;
-use crate::delta::block_level::{compute_delta, DEFAULT_BLOCK_SIZE};
-use crate::delta::file_level::{compute_diff, SyncAction};
-use std::path::Path;
-use tracing::{info, error, debug};
+use crate::transport::{is_windows_remote_path, Transport};
+use crate::scanner::{Scanner, LocalScanner, Manifest, FileEntry, ScanOptions};
+use crate::scanner::filter::ExcludeFilter;
+use crate::remote::agentless::{self, AgentlessRemote};
+use crate::remote::agent::{AgentRemote, AGENT_COMMAND};
+use crate::remote::pool::AgentPool;
+use crate::protocol::{BatchFile, Capabilities};
+use crate::util::hash::hash_reader;
+use crate::util::retry::RetryPolicy;
+use crate::util::xattr::XattrScope;
+use crate::delta::block_level::{block_size_for, DeltaStats, MAX_BLOCK_SIZE};
+use crate::delta::file_level::{compute_diff, count_removed, DiffOptions, SyncAction};
+use crate::apply::local;
+use crate::apply::backup::Backup;
+use crate::apply::metadata::{Metadata, Preserve};
+use crate::apply::partial::Partial;
+use crate::report::Report;
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn, error, debug};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct SyncEngine {
     args: Args,
@@ -23,19 +37,272 @@ impl SyncEngine {
     }
 
     pub fn run(&self) -> Result<()> {
-        // 1. Connect
+        if self.args.block_size == Some(0) {
+            return Err(crate::FastSyncError::Config("--block-size must be greater than 0".into()));
+        }
+        if self.args.block_size.is_some_and(|size| size > MAX_BLOCK_SIZE) {
+            return Err(crate::FastSyncError::Config(format!("--block-size can be at most {}", MAX_BLOCK_SIZE)));
+        }
+        if self.backup(Path::new("")).is_some_and(|b| b.dir.is_none() && b.suffix.is_empty()) {
+            return Err(crate::FastSyncError::Config("--suffix can't be empty without --backup-dir".into()));
+        }
+
+        let source = self.args.source.as_ref().expect("Source required in client mode");
+        let source = source.to_string_lossy();
         let destination = self.args.destination.as_ref().expect("Destination required in client mode");
-        let (user, host, remote_path) = parse_destination(destination)
-            .ok_or_else(|| crate::FastSyncError::Config("Invalid destination format. Expected user@host:path".into()))?;
+
+        let report = Report::new(&source, destination, self.args.dry_run);
+        let result = match (is_local_path(&source), is_local_path(destination)) {
+            (true, true) => self.run_local(Path::new(destination), &report),
+            (true, false) => {
+                let (user, host, remote_path) = parse_destination(destination)
+                    .ok_or_else(|| crate::FastSyncError::Config("Invalid destination format. Expected user@host:path".into()))?;
+                self.run_remote(user, host, remote_path, &report)
+            }
+            (false, true) => {
+                let (user, host, remote_path) = parse_destination(&source)
+                    .ok_or_else(|| crate::FastSyncError::Config("Invalid source format. Expected user@host:path".into()))?;
+                self.run_pull(user, host, remote_path, Path::new(destination), &report)
+            }
+            (false, false) => Err(crate::FastSyncError::Config("Remote-to-remote sync is not supported".into())),
+        };
+        self.write_report(&report, result)
+    }
+
+    /// Print and save what the run did, as asked by --itemize-changes and --report-json.
+    fn write_report(&self, report: &Report, result: Result<()>) -> Result<()> {
+        let summary = report.finish(result.as_ref().err());
+        if self.args.itemize_changes {
+            for line in summary.itemize() {
+                println!("{}", line);
+            }
+        }
+        if let Some(path) = &self.args.report_json {
+            if let Err(e) = summary.write_json(path) {
+                error!("Failed to write report {:?}: {}", path, e);
+                return result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Sync into a plain local path (mounts, removable disks). No SSH involved.
+    fn run_local(&self, dest_path: &Path, report: &Report) -> Result<()> {
+        // 1. Scan Local
+        let source_path = self.args.source.as_ref().expect("Source required in client mode");
+        info!("Scanning local directory: {:?}", source_path);
+        let mut local_scanner = LocalScanner::new(self.scan_options());
+        let local_manifest = self.link_policy(local_scanner.scan(source_path)?);
+        info!("Found {} local items.", local_manifest.entries.len());
+
+        // 2. Scan Destination
+        let dest_manifest = self.drop_backups(self.scan_local_dest(dest_path)?, &local_manifest);
+
+        // 3. Compute Diff
+        info!("Computing differences...");
+        let actions = compute_diff(&local_manifest, &dest_manifest, &self.diff_options());
+        info!("Found {} actions to perform.", actions.len());
+        report.plan(&actions);
+
+        if self.args.dry_run {
+            if !self.args.itemize_changes {
+                print_dry_run(actions);
+            }
+            return Ok(());
+        }
+
+        // 4. Apply
+        let plan = split_actions(actions);
+        let touched = touched_dirs(&local_manifest, &plan);
+        let Plan { uploads, updates, links, mut deletes } = plan;
+        self.check_deletes(&local_manifest, &dest_manifest, &deletes)?;
+        deletes.extend(replaced_links(&uploads, &dest_manifest));
+        let backup = self.backup(dest_path);
+        delete_local(dest_path, deletes, backup.as_ref(), report)?;
+
+        if uploads.is_empty() && updates.is_empty() && links.is_empty() && touched.is_empty() {
+            info!("Sync completed (no uploads).");
+            return Ok(());
+        }
+
+        let pb = self.progress_bar(uploads.len() + updates.len() + links.len());
+        let pool = self.thread_pool()?;
+
+        pool.install(|| {
+            uploads.par_iter().for_each(|entry| {
+                let local_file_path = source_path.join(&entry.path);
+                let dest_file_path = dest_path.join(&entry.path);
+
+                let result = (|| -> Result<DeltaStats> {
+                    if let Some(target) = &entry.link_target {
+                        local::create_symlink(&dest_file_path, target, backup.as_ref())?;
+                        return Ok(DeltaStats::default());
+                    }
+                    if entry.is_dir() {
+                        // Its metadata follows once everything inside is written
+                        local::create_dir(&dest_file_path, backup.as_ref())?;
+                        return Ok(DeltaStats::default());
+                    }
+                    if let Some(pb) = &pb {
+                        pb.set_message(format!("Copying {}", entry.path));
+                    }
+                    let stats = retry_on_mismatch(&entry.path, || {
+                        if self.args.block_level {
+                            local::patch_file(&local_file_path, &dest_file_path, self.block_size(entry.size), backup.as_ref())
+                        } else {
+                            local::copy_file(&local_file_path, &dest_file_path, backup.as_ref()).map(DeltaStats::literal)
+                        }
+                    })?;
+                    self.metadata(entry).apply(&dest_file_path)?;
+                    Ok(stats)
+                })();
+
+                if let Err(e) = &result {
+                    error!("Sync error for {}: {}", entry.path, e);
+                }
+                report.record(&entry.path, &result);
+                if let Some(pb) = &pb { pb.inc(1); }
+            });
+        });
+        link_local(dest_path, &links, backup.as_ref(), report, pb.as_ref());
+        apply_metadata(uploads.iter().chain(touched), &updates, report, pb.as_ref(), |entry| {
+            self.metadata(entry).apply(&dest_path.join(&entry.path))
+        });
+
+        finish(pb, &report.errors())
+    }
+
+    /// Pull a remote tree (user@host:path) into a local directory.
+    fn run_pull(&self, user: &str, host: &str, remote_path: &str, dest_path: &Path, report: &Report) -> Result<()> {
+        // 1. Connect
         let is_windows_remote = is_windows_remote_path(remote_path);
 
         info!("Connecting to {}@{}...", user, host);
-        let ssh_config = SshConfig {
-            host: host.to_string(),
-            port: self.args.port,
-            user: user.to_string(),
-            key_path: self.args.identity.clone(),
+        let ssh_config = self.ssh_config(user, host);
+        let conn = Arc::new(SshConnection::connect(&ssh_config)?);
+        info!("Connected.");
+
+        // 2. Scan Remote
+        info!("Scanning remote directory: {}", remote_path);
+        let remote_manifest = if self.args.block_level {
+            info!("Starting remote agent (scan)...");
+            self.start_agent(&conn)?.scan_with(Path::new(remote_path), self.scan_options())?
+        } else {
+            if self.args.checksum {
+                warn!("--checksum needs the remote agent (--block-level); comparing remote files by mtime");
+            }
+            if self.args.copy_links {
+                warn!("--copy-links needs the remote agent (--block-level) when pulling; remote symlinks are kept as links");
+            }
+            if !self.xattr_scope().is_empty() {
+                warn!("--xattrs/--acls need the remote agent (--block-level); remote attributes are not copied");
+            }
+            // SFTP listings can't be filtered remotely
+            let remote_manifest = AgentlessRemote::new(conn.as_ref()).scan(Path::new(remote_path))?;
+            ExcludeFilter::new(&self.args.exclude)?.apply(remote_manifest)
         };
+        let remote_manifest = self.link_policy(remote_manifest);
+        check_pulled_paths(&remote_manifest)?;
+        info!("Found {} remote items.", remote_manifest.entries.len());
+
+        // 3. Scan Local
+        let dest_manifest = self.drop_backups(self.scan_local_dest(dest_path)?, &remote_manifest);
+
+        // 4. Compute Diff
+        info!("Computing differences...");
+        let actions = compute_diff(&remote_manifest, &dest_manifest, &self.diff_options());
+        info!("Found {} actions to perform.", actions.len());
+        report.plan(&actions);
+
+        if self.args.dry_run {
+            if !self.args.itemize_changes {
+                print_dry_run(actions);
+            }
+            return Ok(());
+        }
+
+        // 5. Apply
+        let plan = split_actions(actions);
+        let touched = touched_dirs(&remote_manifest, &plan);
+        let Plan { uploads: downloads, updates, links, mut deletes } = plan;
+        self.check_deletes(&remote_manifest, &dest_manifest, &deletes)?;
+        deletes.extend(replaced_links(&downloads, &dest_manifest));
+        let backup = self.backup(dest_path);
+        delete_local(dest_path, deletes, backup.as_ref(), report)?;
+
+        if downloads.is_empty() && updates.is_empty() && links.is_empty() && touched.is_empty() {
+            info!("Sync completed (no downloads).");
+            return Ok(());
+        }
+
+        let pb = self.progress_bar(downloads.len() + updates.len() + links.len());
+        let pool = self.thread_pool()?;
+        let remote_path_base = Path::new(remote_path);
+        let agent_pool = self.args.block_level
+            .then(|| AgentPool::new(ssh_config.clone(), self.args.parallel, self.compress_level()));
+
+        pool.install(|| {
+            downloads.par_iter().for_each(|entry| {
+                let remote_file_path = remote_path_base.join(&entry.path);
+                let dest_file_path = dest_path.join(&entry.path);
+
+                let result = (|| -> Result<DeltaStats> {
+                    if let Some(target) = &entry.link_target {
+                        local::create_symlink(&dest_file_path, target, backup.as_ref())?;
+                        return Ok(DeltaStats::default());
+                    }
+                    if entry.is_dir() {
+                        // Its metadata follows once everything inside is written
+                        local::create_dir(&dest_file_path, backup.as_ref())?;
+                        return Ok(DeltaStats::default());
+                    }
+                    if let Some(pb) = &pb {
+                        pb.set_message(format!("Downloading {}", entry.path));
+                    }
+                    let stats = if let Some(agent_pool) = &agent_pool {
+                        let block_size = self.block_size(entry.size);
+                        agent_pool.with_session(|session| {
+                            retry_on_mismatch(&entry.path, || {
+                                let sig = local::signature(&dest_file_path, block_size)?;
+                                local::apply_delta_stream(&dest_file_path, block_size, backup.as_ref(), |apply| {
+                                    session.agent.get_delta_stream(&remote_file_path.to_string_lossy(), sig, apply)
+                                })
+                            })
+                        })?
+                    } else {
+                        retry_on_mismatch(&entry.path, || {
+                            local::write_file(&dest_file_path, backup.as_ref(), |tmp| {
+                                conn.download_file(&remote_file_path, tmp)?;
+                                agentless::verify_checksum(conn.as_ref(), tmp, &remote_file_path.to_string_lossy(), is_windows_remote)
+                            })
+                        })?;
+                        DeltaStats::literal(entry.size)
+                    };
+                    self.metadata(entry).apply(&dest_file_path)?;
+                    Ok(stats)
+                })();
+
+                if let Err(e) = &result {
+                    error!("Sync error for {}: {}", entry.path, e);
+                }
+                report.record(&entry.path, &result);
+                if let Some(pb) = &pb { pb.inc(1); }
+            });
+        });
+        link_local(dest_path, &links, backup.as_ref(), report, pb.as_ref());
+        apply_metadata(downloads.iter().chain(touched), &updates, report, pb.as_ref(), |entry| {
+            self.metadata(entry).apply(&dest_path.join(&entry.path))
+        });
+
+        finish(pb, &report.errors())
+    }
+
+    fn run_remote(&self, user: &str, host: &str, remote_path: &str, report: &Report) -> Result<()> {
+        // 1. Connect
+        let is_windows_remote = is_windows_remote_path(remote_path);
+
+        info!("Connecting to {}@{}...", user, host);
+        let ssh_config = self.ssh_config(user, host);
         
         let conn = Arc::new(SshConnection::connect(&ssh_config)?);
         info!("Connected.");
@@ -43,8 +310,8 @@ impl SyncEngine {
         // 2. Scan Local
         let source_path = self.args.source.as_ref().expect("Source required in client mode");
         info!("Scanning local directory: {:?}", source_path);
-        let mut local_scanner = LocalScanner::new(self.args.exclude.clone());
-        let local_manifest = local_scanner.scan(source_path)?;
+        let mut local_scanner = LocalScanner::new(self.scan_options());
+        let local_manifest = self.link_policy(local_scanner.scan(source_path)?);
         info!("Found {} local items.", local_manifest.entries.len());
 
         // 3. Scan Remote
@@ -55,24 +322,33 @@ impl SyncEngine {
         }
 
         let remote_manifest: Manifest;
+        let mut batching = false;
+        // The scan agent is kept for the deletes
+        let mut scan_agent = None;
 
         if self.args.block_level {
              info!("Starting remote agent (scan)...");
-             let mut agent = match AgentRemote::new(&conn, "fastsync --server") {
-                 Ok(a) => a,
-                 Err(e) => {
-                     error!("Failed to start remote agent. Make sure 'fastsync' is installed on remote and in PATH. Error: {}", e);
-                     return Err(e);
-                 }
-             };
+             let mut agent = self.start_agent(&conn)?;
+             batching = agent.capabilities().contains(Capabilities::BATCH);
              
-             match agent.scan(Path::new(remote_path)) {
+             match agent.scan_with(Path::new(remote_path), self.dest_scan_options()) {
                  Ok(m) => remote_manifest = m,
                  Err(e) => return Err(e),
              }
+             scan_agent = Some(agent);
         } else {
+            if self.args.checksum {
+                warn!("--checksum needs the remote agent (--block-level); comparing remote files by mtime");
+            }
+            // SFTP scans carry no attributes, so there are no metadata-only updates either
+            if !self.xattr_scope().is_empty() {
+                warn!("--xattrs/--acls need the remote agent (--block-level); remote attributes are left alone");
+            }
+            if (self.args.owner || self.args.group) && !self.args.numeric_ids {
+                warn!("--owner/--group without the remote agent (--block-level) match owners by numeric id");
+            }
             let mut remote_scanner = AgentlessRemote::new(conn.as_ref());
-            remote_manifest = match remote_scanner.scan(Path::new(remote_path)) {
+            let scanned = match remote_scanner.scan(Path::new(remote_path)) {
                 Ok(m) => m,
                 Err(e) => {
                     if self.args.dry_run {
@@ -86,67 +362,73 @@ impl SyncEngine {
                     }
                 }
             };
+            // SFTP listings can't be filtered remotely
+            remote_manifest = ExcludeFilter::new(&self.dest_scan_options().excludes)?.apply(scanned);
         }
         
+        let remote_manifest = self.drop_backups(remote_manifest, &local_manifest);
         info!("Found {} remote items.", remote_manifest.entries.len());
 
         // 4. Compute Diff
         info!("Computing differences...");
-        let actions = compute_diff(&local_manifest, &remote_manifest, self.args.delete);
+        let options = DiffOptions {
+            // Windows has no mode bits or numeric owners
+            perms: !is_windows_remote,
+            owner: self.args.owner && !is_windows_remote,
+            group: self.args.group && !is_windows_remote,
+            ..self.diff_options()
+        };
+        let actions = compute_diff(&local_manifest, &remote_manifest, &options);
         info!("Found {} actions to perform.", actions.len());
+        report.plan(&actions);
         
         if self.args.dry_run {
-            for action in actions {
-                match action {
-                    SyncAction::Upload(entry) => println!("UPLOAD: {}", entry.path),
-                    SyncAction::Delete(path) => println!("DELETE: {}", path),
-                }
+            if !self.args.itemize_changes {
+                print_dry_run(actions);
             }
             return Ok(());
         }
 
         // 5. Apply
-        let mut uploads = Vec::new();
-        let mut deletes = Vec::new();
-
-        for action in actions {
-            match action {
-                SyncAction::Upload(entry) => uploads.push(entry),
-                SyncAction::Delete(path) => deletes.push(path),
-            }
-        }
+        let plan = split_actions(actions);
+        // Only the agent sets directory metadata
+        let touched = if self.args.block_level { touched_dirs(&local_manifest, &plan) } else { Vec::new() };
+        let Plan { uploads, updates, links, mut deletes } = plan;
+        self.check_deletes(&local_manifest, &remote_manifest, &deletes)?;
+        deletes.extend(replaced_links(&uploads, &remote_manifest));
+        let backup = self.backup(Path::new(remote_path));
         
         if !deletes.is_empty() {
              info!("Deleting {} files/dirs...", deletes.len());
-             for path in deletes {
-                 let remote_file_path = Path::new(remote_path).join(path);
-                 let remote_file_str = remote_file_path.to_string_lossy();
-                 if is_windows_remote {
-                     let ps_path = escape_powershell_literal(&remote_file_str);
-                     let cmd = format!(
-                         "powershell -NoProfile -NonInteractive -Command \"Remove-Item -LiteralPath '{}' -Force -Recurse -ErrorAction Stop\"",
-                         ps_path
-                     );
-                     conn.exec(&cmd)?;
-                 } else {
-                     let sh_path = escape_posix_literal(&remote_file_str);
-                     let cmd = format!("rm -rf -- '{}'", sh_path);
-                     conn.exec(&cmd)?;
+             let paths: Vec<String> = deletes.iter()
+                 .map(|path| Path::new(remote_path).join(path).to_string_lossy().to_string())
+                 .collect();
+             let result = match (&mut scan_agent, &backup) {
+                 (Some(agent), _) => {
+                     if backup.is_some() {
+                         agent.set_backup(backup.clone())?;
+                     }
+                     agent.delete_paths(&paths)
+                 }
+                 (None, Some(backup)) => {
+                     let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+                     agentless::backup_paths(conn.as_ref(), backup, &paths)
                  }
+                 (None, None) => agentless::delete_paths(conn.as_ref(), &paths, is_windows_remote),
+             }.map(|_| DeltaStats::default());
+             for path in &deletes {
+                 report.record(path, &result);
              }
+             result?;
         }
+        drop(scan_agent);
         
-        if uploads.is_empty() {
+        if uploads.is_empty() && updates.is_empty() && links.is_empty() && touched.is_empty() {
              info!("Sync completed (no uploads).");
              return Ok(());
         }
 
-        let errors = Arc::new(Mutex::new(Vec::new()));
-        let pb = if self.args.progress {
-            let pb = ProgressBar::new(uploads.len() as u64);
-            pb.set_style(ProgressStyle::default_bar().template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}").unwrap());
-            Some(pb)
-        } else { None };
+        let pb = self.progress_bar(uploads.len() + updates.len() + links.len());
 
         let remote_path_base = Path::new(remote_path);
         let source_base = source_path;
@@ -154,161 +436,697 @@ impl SyncEngine {
         if self.args.block_level {
             info!("Syncing with Block-Level incremental (Parallel)...");
             
-            let pool = rayon::ThreadPoolBuilder::new()
-                .num_threads(self.args.parallel)
-                .build()
-                .map_err(|e| crate::FastSyncError::Config(format!("Failed to build thread pool: {}", e)))?;
+            let pool = self.thread_pool()?;
                 
-            let ssh_config = ssh_config.clone();
-            let agent_pool = Arc::new(Mutex::new(Vec::new()));
+            let agent_pool = AgentPool::new(ssh_config.clone(), self.args.parallel, self.compress_level())
+                .with_backup(backup.clone())
+                .with_partial(self.partial());
+
+            // New files need no signature, unless a partial one may be waiting
+            let remote_files: HashSet<&str> = remote_manifest.entries.iter()
+                .filter(|e| e.is_file())
+                .map(|e| e.path.as_str())
+                .collect();
+            let resuming = self.partial().is_some();
+
+            // Directories first, all in one pipeline, so nothing after needs a round
+            // trip for its parent
+            let (dirs, uploads): (Vec<FileEntry>, Vec<FileEntry>) = uploads.into_iter().partition(|e| e.is_dir());
+            if !dirs.is_empty() {
+                debug!("Creating {} remote directories", dirs.len());
+                let paths: Vec<String> = dirs.iter()
+                    .map(|e| remote_path_base.join(&e.path).to_string_lossy().to_string())
+                    .collect();
+                match agent_pool.with_session(|session| session.agent.make_dirs(&paths)) {
+                    Ok(outcomes) => for (entry, result) in dirs.iter().zip(outcomes) {
+                        record_outcome(entry, &result.map(|_| DeltaStats::default()), report, pb.as_ref());
+                    },
+                    Err(e) => {
+                        let failed = Err(e);
+                        for entry in &dirs {
+                            record_outcome(entry, &failed, report, pb.as_ref());
+                        }
+                    }
+                }
+            }
+
+            // Small files go whole, many per request, instead of one delta round trip each
+            let (small_files, uploads): (Vec<FileEntry>, Vec<FileEntry>) = uploads.into_iter()
+                .partition(|e| batching && e.is_file() && e.size <= BATCH_FILE_LIMIT);
+            if !small_files.is_empty() {
+                debug!("Sending {} small files in batches", small_files.len());
+                let per_worker = small_files.len().div_ceil(self.args.parallel.max(1));
+                pool.install(|| {
+                    small_files.par_chunks(per_worker).for_each(|run| {
+                        let by_remote_path: HashMap<String, &FileEntry> = run.iter()
+                            .map(|e| (remote_path_base.join(&e.path).to_string_lossy().to_string(), e))
+                            .collect();
+                        // Files with an outcome; a retried session only resends the rest
+                        let mut done = HashSet::new();
+                        let result = agent_pool.with_session(|session| {
+                            let pending: Vec<FileEntry> = run.iter()
+                                .filter(|e| !done.contains(&e.path))
+                                .cloned()
+                                .collect();
+                            let mut read_failed = Vec::new();
+                            let batches = small_file_batches(&pending, source_base, remote_path_base, self.preserve(), |entry, e| {
+                                error!("Sync error for {}: {}", entry.path, e);
+                                report.record(&entry.path, &Err(e.into()));
+                                if let Some(pb) = &pb { pb.inc(1); }
+                                read_failed.push(entry.path.clone());
+                            });
+                            let result = session.agent.write_batches(batches, |path, result| {
+                                let Some(entry) = by_remote_path.get(path) else { return };
+                                done.insert(entry.path.clone());
+                                let result = result.map(|_| DeltaStats::literal(entry.size));
+                                if let Err(e) = &result {
+                                    error!("Sync error for {}: {}", entry.path, e);
+                                }
+                                report.record(&entry.path, &result);
+                                if let Some(pb) = &pb { pb.inc(1); }
+                            });
+                            done.extend(read_failed);
+                            result
+                        });
+
+                        if let Err(e) = result {
+                            error!("Batch transfer failed: {}", e);
+                            let failed = Err(e);
+                            for entry in run.iter().filter(|e| !done.contains(&e.path)) {
+                                report.record(&entry.path, &failed);
+                                if let Some(pb) = &pb { pb.inc(1); }
+                            }
+                        }
+                    });
+                });
+            }
             
             pool.install(|| {
                 uploads.par_iter().for_each(|entry| {
-                     let mut ctx = {
-                         let mut pool = agent_pool.lock().unwrap();
-                         pool.pop()
-                     };
-                     
-                     if ctx.is_none() {
-                         match SshConnection::connect(&ssh_config) {
-                             Ok(c) => ctx = Some(c),
-                             Err(e) => {
-                                 error!("Failed to connect: {}", e);
-                                 errors.lock().unwrap().push(format!("{}: Connect failed", entry.path));
-                                 return;
-                             }
-                         }
-                     }
-                     
-                     let context_conn = ctx.unwrap();
-                     
-                     let result = (|| -> Result<()> {
-                         let mut agent = AgentRemote::new(&context_conn, "fastsync --server")?;
-                         
-                         let local_file_path = source_base.join(&entry.path);
-                         let remote_file_path = remote_path_base.join(&entry.path);
-                         let remote_path_str = remote_file_path.to_string_lossy().to_string();
-
-                         if entry.is_dir {
-                             debug!("Creating remote directory: {}", remote_path_str);
-                             context_conn.create_dir_all(Path::new(&remote_path_str))?;
+                     let local_file_path = source_base.join(&entry.path);
+                     let remote_file_path = remote_path_base.join(&entry.path);
+                     let remote_path_str = remote_file_path.to_string_lossy().to_string();
+
+                     let result = agent_pool.with_session(|session| {
+                         let mut stats = DeltaStats::default();
+                         if let Some(target) = &entry.link_target {
+                             session.agent.symlink(&remote_path_str, target)?;
                          } else {
                             if let Some(pb) = &pb { pb.set_message(format!("Syncing file {}", entry.path)); }
-                            
-                            if let Some(parent) = remote_file_path.parent() {
-                                context_conn.create_dir_all(parent)?;
-                            }
 
-                            let sig = agent.get_signature(&remote_path_str, DEFAULT_BLOCK_SIZE)
-                                .unwrap_or_else(|_| {
-                                     crate::delta::block_level::FileSignature {
-                                         blocks: vec![],
-                                         block_size: DEFAULT_BLOCK_SIZE,
-                                         file_size: 0,
-                                     }
-                                });
-                            
-                            let local_data = std::fs::read(&local_file_path).map_err(crate::FastSyncError::Io)?;
-                            let delta = compute_delta(&local_data, &sig);
-                            
-                            agent.apply_delta(&remote_path_str, delta)?;
-                            
-                            if is_windows_remote {
-                                let ps_path = escape_powershell_literal(&remote_path_str);
-                                let cmd = format!(
-                                    "powershell -NoProfile -NonInteractive -Command \"(Get-Item -LiteralPath '{}').LastWriteTimeUtc = [DateTimeOffset]::FromUnixTimeSeconds({}).UtcDateTime\"",
-                                    ps_path,
-                                    entry.mtime
-                                );
-                                context_conn.exec(&cmd).ok();
-                            } else {
-                                let sh_path = escape_posix_literal(&remote_path_str);
-                                let cmd = format!("touch -d @{} '{}'", entry.mtime, sh_path);
-                                context_conn.exec(&cmd).ok();
-                            }
+                            // Two round trips: the signature, then the pipelined delta and metadata
+                            let block_size = self.block_size(entry.size);
+                            let on_remote = remote_files.contains(entry.path.as_str());
+                            let nothing = || crate::delta::block_level::FileSignature { blocks: vec![], block_size, file_size: 0 };
+                            stats = retry_on_mismatch(&entry.path, || {
+                                let sig = if !on_remote && !resuming {
+                                    nothing()
+                                } else {
+                                    match session.agent.get_signature(&remote_path_str, block_size) {
+                                        Ok(sig) => sig,
+                                        // Older agents answer for a missing file with an error
+                                        Err(crate::FastSyncError::RemoteCommand(_)) if !on_remote => nothing(),
+                                        Err(e) => return Err(e),
+                                    }
+                                };
+
+                                let local_file = std::fs::File::open(&local_file_path).map_err(crate::FastSyncError::Io)?;
+                                session.agent.apply_delta_stream(&remote_path_str, std::io::BufReader::new(local_file), sig, &self.metadata(entry))
+                            })?;
                          }
-                         Ok(())
-                     })();
+                         Ok(stats)
+                     });
 
-                     if let Err(e) = result {
+                     if let Err(e) = &result {
                          error!("Sync error for {}: {}", entry.path, e);
-                         errors.lock().unwrap().push(format!("{}: {}", entry.path, e));
-                     } else {
-                         agent_pool.lock().unwrap().push(context_conn);
                      }
+                     report.record(&entry.path, &result);
                      
                      if let Some(pb) = &pb { pb.inc(1); }
                 });
             });
+
+            // Links go last, once the files they point at are in place
+            for (entry, target) in &links {
+                let remote_file_path = remote_path_base.join(&entry.path).to_string_lossy().to_string();
+                let remote_target = remote_path_base.join(target).to_string_lossy().to_string();
+                let result = agent_pool.with_session(|session| {
+                    session.agent.hard_link(&remote_file_path, &remote_target)
+                }).map(|_| DeltaStats::default());
+                record_outcome(entry, &result, report, pb.as_ref());
+            }
+            apply_metadata(dirs.iter().chain(touched), &updates, report, pb.as_ref(), |entry| {
+                let remote_file_path = remote_path_base.join(&entry.path).to_string_lossy().to_string();
+                agent_pool.with_session(|session| session.agent.set_metadata(&remote_file_path, &self.metadata(entry)))
+            });
         } else {
             // Parallel Uploads (File Level)
-            let pool = rayon::ThreadPoolBuilder::new()
-                .num_threads(self.args.parallel)
-                .build()
-                .map_err(|e| crate::FastSyncError::Config(format!("Failed to build thread pool: {}", e)))?;
-                
+            let pool = self.thread_pool()?;
+            // Remote files that get replaced, and so need a backup first
+            let existing: HashSet<&str> = remote_manifest.entries.iter()
+                .filter(|e| !e.is_dir())
+                .map(|e| e.path.as_str())
+                .collect();
+
+            // Directories first, over one SFTP session, so files needn't create their parents
+            let (dirs, uploads): (Vec<FileEntry>, Vec<FileEntry>) = uploads.into_iter().partition(|e| e.is_dir());
+            let dir_paths: Vec<PathBuf> = dirs.iter().map(|e| remote_path_base.join(&e.path)).collect();
+            for (entry, result) in dirs.iter().zip(conn.create_dirs(&dir_paths)) {
+                record_outcome(entry, &result.map(|_| DeltaStats::default()), report, pb.as_ref());
+            }
+
             pool.install(|| {
                 uploads.par_iter().for_each(|entry| {
                     let local_file_path = source_base.join(&entry.path);
                     let remote_file_path = remote_path_base.join(&entry.path);
                     
-                    let result = (|| -> Result<()> {
-                        if entry.is_dir {
-                             conn.create_dir_all(&remote_file_path)?;
+                    let result = (|| -> Result<DeltaStats> {
+                        if let Some(target) = &entry.link_target {
+                             if let Some(backup) = backup.as_ref().filter(|_| existing.contains(entry.path.as_str())) {
+                                 agentless::backup_paths(conn.as_ref(), backup, std::slice::from_ref(&remote_file_path))?;
+                             }
+                             conn.symlink(target, &remote_file_path)?;
+                             return Ok(DeltaStats::default());
                         } else {
                              if let Some(pb) = &pb {
                                  pb.set_message(format!("Uploading {}", entry.path));
                              }
-                             if let Some(parent) = remote_file_path.parent() {
-                                 conn.create_dir_all(parent)?;
-                             }
-                             conn.upload_file(&local_file_path, &remote_file_path)?;
+                             // The old file is moved aside once, and only when its replacement
+                             // is complete and checked
+                             let mut to_back_up = backup.as_ref().filter(|_| existing.contains(entry.path.as_str()));
+                             let mut save_backup = || -> Result<()> {
+                                 if let Some(backup) = to_back_up {
+                                     agentless::backup_paths(conn.as_ref(), backup, std::slice::from_ref(&remote_file_path))?;
+                                     to_back_up = None;
+                                 }
+                                 Ok(())
+                             };
                              let remote_path_str = remote_file_path.to_string_lossy();
-                             if is_windows_remote {
-                                 let ps_path = escape_powershell_literal(&remote_path_str);
-                                 let cmd = format!(
-                                     "powershell -NoProfile -NonInteractive -Command \"(Get-Item -LiteralPath '{}').LastWriteTimeUtc = [DateTimeOffset]::FromUnixTimeSeconds({}).UtcDateTime\"",
-                                     ps_path,
-                                     entry.mtime
-                                 );
-                                 conn.exec(&cmd).ok();
-                             } else {
-                                 let sh_path = escape_posix_literal(&remote_path_str);
-                                 let cmd = format!(
-                                     "touch -d @{} '{}' && chmod {:o} '{}'", 
-                                     entry.mtime,
-                                     sh_path,
-                                     entry.mode & 0o777,
-                                     sh_path
-                                 );
-                                 conn.exec(&cmd).ok();
-                             }
+                             retry_on_mismatch(&entry.path, || {
+                                 if self.args.inplace {
+                                     // No temp file: the old one goes before it is overwritten
+                                     save_backup()?;
+                                     conn.upload_file(&local_file_path, &remote_file_path)?;
+                                     return agentless::verify_checksum(conn.as_ref(), &local_file_path, &remote_path_str, is_windows_remote);
+                                 }
+                                 conn.upload_file_with(&local_file_path, &remote_file_path, |tmp| {
+                                     agentless::verify_checksum(conn.as_ref(), &local_file_path, &tmp.to_string_lossy(), is_windows_remote)?;
+                                     save_backup()
+                                 })
+                             })?;
+                             conn.set_metadata(&remote_file_path, &self.sftp_metadata(entry, is_windows_remote))?;
                         }
-                        Ok(())
+                        Ok(DeltaStats::literal(entry.size))
                     })();
                     
-                    if let Err(e) = result {
+                    if let Err(e) = &result {
                         error!("Sync error for {}: {}", entry.path, e);
-                        errors.lock().unwrap().push(format!("{}: {}", entry.path, e));
                     }
+                    report.record(&entry.path, &result);
                     if let Some(pb) = &pb { pb.inc(1); }
                 });
             });
+
+            for (entry, target) in &links {
+                let remote_file_path = remote_path_base.join(&entry.path);
+                let remote_target = remote_path_base.join(target);
+                let result = (|| -> Result<DeltaStats> {
+                    if let Some(backup) = backup.as_ref().filter(|_| existing.contains(entry.path.as_str())) {
+                        agentless::backup_paths(conn.as_ref(), backup, std::slice::from_ref(&remote_file_path))?;
+                    }
+                    agentless::hard_link(conn.as_ref(), &remote_target.to_string_lossy(), &remote_file_path.to_string_lossy(), is_windows_remote)?;
+                    Ok(DeltaStats::default())
+                })();
+                record_outcome(entry, &result, report, pb.as_ref());
+            }
+            // Modes, owners and groups; xattrs need the agent
+            apply_metadata(std::iter::empty(), &updates, report, pb.as_ref(), |entry| {
+                conn.set_metadata(&remote_path_base.join(&entry.path), &self.sftp_metadata(entry, is_windows_remote))
+            });
         }
 
-        if let Some(pb) = &pb {
-            pb.finish_with_message("Done");
+        finish(pb, &report.errors())
+    }
+
+    /// Used for every scan, local or remote, so excluded paths look the same
+    /// on both sides and are never deleted by --delete.
+    fn scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            checksum: self.args.checksum,
+            excludes: self.args.exclude.clone(),
+            gitignore: !self.args.no_gitignore,
+            owner_names: (self.args.owner || self.args.group) && !self.args.numeric_ids,
+            copy_links: self.args.copy_links,
+            xattrs: self.xattr_scope(),
         }
-        
-        let final_errors = errors.lock().unwrap();
-        if !final_errors.is_empty() {
-            error!("Encoutered {} errors during sync.", final_errors.len());
-            return Err(crate::FastSyncError::Io(std::io::Error::new(std::io::ErrorKind::Other, "Sync completed with errors")));
+    }
+
+    /// Leave out source symlinks pointing outside the tree with --safe-links.
+    fn link_policy(&self, mut manifest: Manifest) -> Manifest {
+        if self.args.safe_links {
+            manifest.entries.retain(|e| {
+                let safe = e.link_stays_inside();
+                if !safe {
+                    info!("Ignoring unsafe symlink {} -> {}", e.path, e.link_target.as_deref().unwrap_or_default());
+                }
+                safe
+            });
+        }
+        manifest
+    }
+
+    /// What to carry over besides mtime and mode (--owner, --group, --xattrs, --acls).
+    fn preserve(&self) -> Preserve {
+        Preserve { owner: self.args.owner, group: self.args.group, xattrs: self.xattr_scope() }
+    }
+
+    fn xattr_scope(&self) -> XattrScope {
+        XattrScope { xattrs: self.args.xattrs, acls: self.args.acls }
+    }
+
+    /// Metadata to apply to the destination copy of `entry`.
+    fn metadata(&self, entry: &FileEntry) -> Metadata {
+        Metadata::new(entry, self.preserve())
+    }
+
+    /// Metadata set over SFTP, which Windows remotes only take mtimes from.
+    fn sftp_metadata(&self, entry: &FileEntry, is_windows_remote: bool) -> Metadata {
+        let metadata = self.metadata(entry);
+        if is_windows_remote {
+            Metadata { mode: 0, owner: None, group: None, ..metadata }
+        } else {
+            metadata
+        }
+    }
+
+    /// Safety checks before anything is deleted: an empty source, --max-delete,
+    /// --max-delete-percent, and a confirmation prompt when run interactively.
+    fn check_deletes(&self, source: &Manifest, dest: &Manifest, deletes: &[String]) -> Result<()> {
+        if deletes.is_empty() {
+            return Ok(());
+        }
+        if source.entries.is_empty() {
+            return Err(crate::FastSyncError::DeleteRefused(format!(
+                "source {} is empty; this would wipe the destination", source.root_path)));
+        }
+
+        let removed = count_removed(deletes, dest);
+        if let Some(max) = self.args.max_delete {
+            if removed > max {
+                return Err(crate::FastSyncError::DeleteRefused(format!(
+                    "{} files would be deleted, more than --max-delete {}", removed, max)));
+            }
+        }
+        if let Some(percent) = self.args.max_delete_percent {
+            if removed * 100 > usize::from(percent) * dest.entries.len() {
+                return Err(crate::FastSyncError::DeleteRefused(format!(
+                    "{} of {} destination files would be deleted, more than --max-delete-percent {}",
+                    removed, dest.entries.len(), percent)));
+            }
         }
 
-        info!("Sync completed successfully.");
+        let interactive = std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+        if interactive && !self.args.yes && !confirm_deletes(deletes, removed)? {
+            return Err(crate::FastSyncError::DeleteRefused("not confirmed".into()));
+        }
         Ok(())
     }
+
+    /// Scan options for the receiving side. With --delete-excluded the destination
+    /// is scanned unfiltered, so excluded files there are deleted too.
+    /// A backup dir and partial files are always left out.
+    fn dest_scan_options(&self) -> ScanOptions {
+        let mut options = if self.args.delete_excluded {
+            ScanOptions { excludes: Vec::new(), gitignore: false, ..self.scan_options() }
+        } else {
+            self.scan_options()
+        };
+        // Links on the receiving side are what gets replaced, never followed
+        options.copy_links = false;
+        if let Some(pattern) = self.backup(Path::new("")).and_then(|b| b.exclude_pattern()) {
+            options.excludes.push(pattern);
+        }
+        if let Some(pattern) = self.partial().and_then(|p| p.exclude_pattern()) {
+            options.excludes.push(pattern);
+        }
+        options
+    }
+
+    /// Leave backups kept next to their files out of the destination manifest.
+    fn drop_backups(&self, dest: Manifest, source: &Manifest) -> Manifest {
+        match self.backup(Path::new("")) {
+            Some(backup) => backup.drop_from(dest, source),
+            None => dest,
+        }
+    }
+
+    /// Backup settings for a destination rooted at `dest_root`, if backups are on.
+    fn backup(&self, dest_root: &Path) -> Option<Backup> {
+        if !self.args.backup && self.args.backup_dir.is_none() {
+            return None;
+        }
+        let dir = self.args.backup_dir.as_ref().map(PathBuf::from);
+        // Next to the file a suffix is needed; inside a backup dir the name can stay
+        let default_suffix = if dir.is_some() { "" } else { "~" };
+        Some(Backup {
+            root: dest_root.to_path_buf(),
+            dir,
+            suffix: self.args.suffix.clone().unwrap_or_else(|| default_suffix.to_string()),
+        })
+    }
+
+    /// Where interrupted uploads are kept, if --partial is on.
+    fn partial(&self) -> Option<Partial> {
+        (self.args.partial || self.args.partial_dir.is_some())
+            .then(|| Partial { dir: self.args.partial_dir.as_ref().map(PathBuf::from) })
+    }
+
+    fn diff_options(&self) -> DiffOptions {
+        DiffOptions {
+            delete: self.args.delete || self.args.delete_excluded,
+            checksum: self.args.checksum,
+            modify_window: self.args.modify_window,
+            hard_links: self.args.hard_links,
+            perms: true,
+            owner: self.args.owner,
+            group: self.args.group,
+        }
+    }
+
+    /// Block size for a file: `--block-size` if given, otherwise picked from the file size.
+    fn block_size(&self, file_size: u64) -> usize {
+        self.args.block_size.unwrap_or_else(|| block_size_for(file_size))
+    }
+
+    /// zstd level for agent frames, when `--compress` is on.
+    fn compress_level(&self) -> Option<i32> {
+        self.args.compress.then_some(self.args.compress_level)
+    }
+
+    /// Start the agent for scanning and check it supports the options in use.
+    fn start_agent(&self, conn: &SshConnection) -> Result<AgentRemote> {
+        let agent = AgentRemote::new(conn, AGENT_COMMAND, self.compress_level()).inspect_err(|e| {
+            error!("Failed to start remote agent. Make sure 'fastsync' is installed on remote and in PATH. Error: {}", e);
+        })?;
+        if self.args.checksum {
+            agent.require(Capabilities::CHECKSUMS)?;
+        }
+        Ok(agent)
+    }
+
+    fn ssh_config(&self, user: &str, host: &str) -> SshConfig {
+        SshConfig {
+            host: host.to_string(),
+            port: self.args.port,
+            user: user.to_string(),
+            key_path: self.args.identity.clone(),
+            // The agent compresses its own frames; SFTP transfers rely on SSH compression
+            compress: self.args.compress && !self.args.block_level,
+            inplace: self.args.inplace,
+            partial: self.partial(),
+            retry: RetryPolicy { retries: self.args.retries, delay: Duration::from_secs(self.args.retry_delay) },
+        }
+    }
+
+    /// Scan a local destination directory, creating it first unless this is a dry run.
+    fn scan_local_dest(&self, dest_path: &Path) -> Result<Manifest> {
+        info!("Scanning destination directory: {:?}", dest_path);
+        if !self.args.dry_run {
+            std::fs::create_dir_all(dest_path)?;
+        }
+
+        let dest_manifest = if dest_path.is_dir() {
+            LocalScanner::new(self.dest_scan_options()).scan(dest_path)?
+        } else {
+            Manifest {
+                generated_at: 0,
+                root_path: dest_path.to_string_lossy().to_string(),
+                entries: vec![],
+            }
+        };
+        info!("Found {} destination items.", dest_manifest.entries.len());
+        Ok(dest_manifest)
+    }
+
+    fn progress_bar(&self, len: usize) -> Option<ProgressBar> {
+        if self.args.progress {
+            let pb = ProgressBar::new(len as u64);
+            pb.set_style(ProgressStyle::default_bar().template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}").unwrap());
+            Some(pb)
+        } else { None }
+    }
+
+    fn thread_pool(&self) -> Result<rayon::ThreadPool> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.args.parallel)
+            .build()
+            .map_err(|e| crate::FastSyncError::Config(format!("Failed to build thread pool: {}", e)))
+    }
+}
+
+/// Files up to this size are sent whole in `Batch` requests
+const BATCH_FILE_LIMIT: u64 = 64 * 1024;
+/// Upper bounds for one `Batch` request
+const BATCH_MAX_FILES: usize = 256;
+const BATCH_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Read `entries` into `Batch`-sized groups of whole files, lazily.
+/// Files that can't be read are reported to `on_error` and left out.
+fn small_file_batches<'a, E>(
+    entries: &'a [FileEntry],
+    source_base: &'a Path,
+    remote_base: &'a Path,
+    preserve: Preserve,
+    mut on_error: E,
+) -> impl Iterator<Item = Vec<BatchFile>> + 'a
+where
+    E: FnMut(&FileEntry, std::io::Error) + 'a,
+{
+    let mut entries = entries.iter().peekable();
+    std::iter::from_fn(move || {
+        let mut batch = Vec::new();
+        let mut bytes = 0;
+        while let Some(entry) = entries.peek() {
+            if !batch.is_empty() && (batch.len() >= BATCH_MAX_FILES || bytes + entry.size > BATCH_MAX_BYTES) {
+                break;
+            }
+            let entry = entries.next().unwrap();
+            match std::fs::read(source_base.join(&entry.path)) {
+                Ok(data) => {
+                    bytes += data.len() as u64;
+                    batch.push(BatchFile {
+                        path: remote_base.join(&entry.path).to_string_lossy().to_string(),
+                        checksum: hash_reader(&mut data.as_slice()).expect("reading from memory"),
+                        data,
+                        metadata: Metadata::new(entry, preserve),
+                    });
+                }
+                Err(e) => on_error(entry, e),
+            }
+        }
+        (!batch.is_empty()).then_some(batch)
+    })
+}
+
+/// Transfers retried when the written file fails checksum verification
+const CHECKSUM_RETRIES: usize = 2;
+
+/// Re-run a transfer whose result failed checksum verification.
+fn retry_on_mismatch<T, F>(path: &str, mut transfer: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut attempt = 0;
+    loop {
+        match transfer() {
+            Err(crate::FastSyncError::ChecksumMismatch { .. }) if attempt < CHECKSUM_RETRIES => {
+                attempt += 1;
+                warn!("Checksum mismatch for {}, retrying ({}/{})", path, attempt, CHECKSUM_RETRIES);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn delete_local(dest_path: &Path, deletes: Vec<String>, backup: Option<&Backup>, report: &Report) -> Result<()> {
+    if !deletes.is_empty() {
+        info!("Deleting {} files/dirs...", deletes.len());
+        for path in deletes {
+            let result = local::remove_path(&dest_path.join(&path), backup).map(|_| DeltaStats::default());
+            report.record(&path, &result);
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Link each entry to the already synced file it shares an inode with in the source.
+fn link_local(dest_path: &Path, links: &[(FileEntry, String)], backup: Option<&Backup>, report: &Report, pb: Option<&ProgressBar>) {
+    for (entry, target) in links {
+        let result = local::hard_link(&dest_path.join(target), &dest_path.join(&entry.path), backup)
+            .map(|_| DeltaStats::default());
+        record_outcome(entry, &result, report, pb);
+    }
+}
+
+/// Apply the metadata of `updates` and of the directories among `done`, which
+/// are already counted (uploaded, or written into).
+/// Directories go last and deepest first: one made read-only would refuse
+/// the entries still to be written into it.
+fn apply_metadata<'a, F>(done: impl IntoIterator<Item = &'a FileEntry>, updates: &'a [FileEntry], report: &Report, pb: Option<&ProgressBar>, apply: F)
+where
+    F: Fn(&FileEntry) -> Result<()>,
+{
+    for entry in updates.iter().filter(|e| !e.is_dir()) {
+        record_outcome(entry, &apply(entry).map(|_| DeltaStats::default()), report, pb);
+    }
+    // Updates are still to be counted; the rest already are
+    let mut dirs: Vec<(&FileEntry, bool)> = done.into_iter()
+        .filter(|e| e.is_dir())
+        .map(|e| (e, false))
+        .chain(updates.iter().filter(|e| e.is_dir()).map(|e| (e, true)))
+        .collect();
+    dirs.sort_by_key(|(e, _)| std::cmp::Reverse(e.path.matches('/').count()));
+    for (entry, update) in dirs {
+        let result = apply(entry).map(|_| DeltaStats::default());
+        if update {
+            record_outcome(entry, &result, report, pb);
+        } else if let Err(e) = &result {
+            error!("Sync error for {}: {}", entry.path, e);
+            report.record(&entry.path, &result);
+        }
+    }
+}
+
+/// Source directories the run writes into or deletes from, and whose metadata
+/// isn't applied anyway. That changes their mtime, and read-only ones get owner
+/// write permission meanwhile (`local::writable_parent`), so their own metadata
+/// is applied again at the end.
+fn touched_dirs<'a>(source: &'a Manifest, plan: &Plan) -> Vec<&'a FileEntry> {
+    let written: HashSet<&str> = plan.uploads.iter().map(|e| e.path.as_str())
+        .chain(plan.links.iter().map(|(e, _)| e.path.as_str()))
+        .chain(plan.deletes.iter().map(String::as_str))
+        .filter_map(|path| path.rsplit_once('/').map(|(parent, _)| parent))
+        .collect();
+    let applied: HashSet<&str> = plan.uploads.iter()
+        .chain(&plan.updates)
+        .filter(|e| e.is_dir())
+        .map(|e| e.path.as_str())
+        .collect();
+    source.entries.iter()
+        .filter(|e| e.is_dir() && written.contains(e.path.as_str()) && !applied.contains(e.path.as_str()))
+        .collect()
+}
+
+fn record_outcome(entry: &FileEntry, result: &Result<DeltaStats>, report: &Report, pb: Option<&ProgressBar>) {
+    if let Err(e) = result {
+        error!("Sync error for {}: {}", entry.path, e);
+    }
+    report.record(&entry.path, result);
+    if let Some(pb) = pb { pb.inc(1); }
+}
+
+/// Deletions listed in the confirmation prompt before the rest is summarized
+const CONFIRM_LIST_LIMIT: usize = 50;
+
+/// List the deletions and ask on the terminal whether to go ahead.
+fn confirm_deletes(deletes: &[String], removed: usize) -> Result<bool> {
+    println!("The following will be deleted from the destination:");
+    for path in deletes.iter().take(CONFIRM_LIST_LIMIT) {
+        println!("  {}", path);
+    }
+    if deletes.len() > CONFIRM_LIST_LIMIT {
+        println!("  ... and {} more", deletes.len() - CONFIRM_LIST_LIMIT);
+    }
+    print!("Delete {} files? [y/N] ", removed);
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn print_dry_run(actions: Vec<SyncAction>) {
+    for action in actions {
+        match action {
+            SyncAction::Upload(entry, _) => println!("UPLOAD: {}", entry.path),
+            SyncAction::Metadata(entry, _) => println!("UPDATE: {}", entry.path),
+            SyncAction::HardLink(entry, target) => println!("LINK: {} => {}", entry.path, target),
+            SyncAction::Delete(path) => println!("DELETE: {}", path),
+        }
+    }
+}
+
+/// Uploads, hard links (entry, path of the file it links to) and deletes.
+/// The actions of a diff, by the phase that carries them out.
+#[derive(Default)]
+struct Plan {
+    uploads: Vec<FileEntry>,
+    /// Entries whose content is in place but whose metadata isn't
+    updates: Vec<FileEntry>,
+    /// Entries to hard-link to the path of another name of the same file
+    links: Vec<(FileEntry, String)>,
+    deletes: Vec<String>,
+}
+
+fn split_actions(actions: Vec<SyncAction>) -> Plan {
+    let mut plan = Plan::default();
+    for action in actions {
+        match action {
+            SyncAction::Upload(entry, _) => plan.uploads.push(entry),
+            SyncAction::Metadata(entry, _) => plan.updates.push(entry),
+            SyncAction::HardLink(entry, target) => plan.links.push((entry, target)),
+            SyncAction::Delete(path) => plan.deletes.push(path),
+        }
+    }
+    plan
+}
+
+/// Destination symlinks about to become directories. They are removed with the
+/// deletes, before any upload could create files behind them.
+fn replaced_links(uploads: &[FileEntry], dest: &Manifest) -> Vec<String> {
+    let links: HashSet<&str> = dest.entries.iter()
+        .filter(|e| e.is_symlink())
+        .map(|e| e.path.as_str())
+        .collect();
+    uploads.iter()
+        .filter(|e| e.is_dir() && links.contains(e.path.as_str()))
+        .map(|e| e.path.clone())
+        .collect()
+}
+
+/// Refuse a remote manifest that would have a pull write outside the destination:
+/// every path must be plain and relative, and none may lie beneath a symlink of
+/// the same manifest, which the pull creates before writing through it.
+fn check_pulled_paths(manifest: &Manifest) -> Result<()> {
+    let links: HashSet<&str> = manifest.entries.iter()
+        .filter(|e| e.is_symlink())
+        .map(|e| e.path.as_str())
+        .collect();
+    for entry in &manifest.entries {
+        let path = Path::new(&entry.path);
+        if entry.path.is_empty() || !path.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+            return Err(crate::FastSyncError::Protocol(format!("Remote sent an unsafe path {:?}", entry.path)));
+        }
+        if let Some(link) = path.ancestors().skip(1).filter_map(Path::to_str).find(|dir| links.contains(dir)) {
+            return Err(crate::FastSyncError::Protocol(format!("Remote path {:?} lies beneath the symlink {:?}", entry.path, link)));
+        }
+    }
+    Ok(())
+}
+
+fn finish(pb: Option<ProgressBar>, errors: &[String]) -> Result<()> {
+    if let Some(pb) = &pb {
+        pb.finish_with_message("Done");
+    }
+
+    if !errors.is_empty() {
+        error!("Encoutered {} errors during sync.", errors.len());
+        return Err(crate::FastSyncError::PartialTransfer { failed: errors.len() });
+    }
+
+    info!("Sync completed successfully.");
+    Ok(())
 }
 
 fn parse_destination(dest: &str) -> Option<(&str, &str, &str)> {
@@ -324,15 +1142,46 @@ fn parse_destination(dest: &str) -> Option<(&str, &str, &str)> {
     Some((user, host, remote_path))
 }
 
-fn is_windows_remote_path(remote_path: &str) -> bool {
-    let bytes = remote_path.as_bytes();
-    bytes.len() > 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic()
+/// rsync-style rule: a path is remote only if a `:` appears before any path separator.
+/// Drive letters (`C:\dir`, `D:/dir`) are local.
+pub fn is_local_path(path: &str) -> bool {
+    match path.find(':') {
+        None => true,
+        Some(1) if path.as_bytes()[0].is_ascii_alphabetic() => true,
+        Some(idx) => path[..idx].contains(['/', '\\']),
+    }
 }
 
-fn escape_posix_literal(value: &str) -> String {
-    value.replace('\'', "'\\''")
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_kind() {
+        assert!(is_local_path("/mnt/backup"));
+        assert!(is_local_path("./out"));
+        assert!(is_local_path("D:/www"));
+        assert!(is_local_path("C:\\www"));
+        assert!(is_local_path("./odd:name"));
+        assert!(!is_local_path("user@host:/srv"));
+        assert!(!is_local_path("host:/srv"));
+
+        assert_eq!(parse_destination("user@host:D:/www"), Some(("user", "host", "D:/www")));
+        assert_eq!(parse_destination("host:/srv"), None);
+    }
 
-fn escape_powershell_literal(value: &str) -> String {
-    value.replace('\'', "''")
+    #[test]
+    fn test_check_pulled_paths() {
+        let manifest = |entries: Vec<FileEntry>| Manifest { entries, ..Default::default() };
+        let file = |path: &str| FileEntry { path: path.into(), ..Default::default() };
+        let link = |path: &str| FileEntry {
+            path: path.into(), kind: crate::scanner::FileKind::Symlink, link_target: Some("/etc".into()), ..Default::default()
+        };
+
+        assert!(check_pulled_paths(&manifest(vec![file("a"), file("a/b.txt"), link("etc")])).is_ok());
+        for path in ["../outside", "a/../../outside", "/etc/passwd", "./a", ""] {
+            assert!(check_pulled_paths(&manifest(vec![file(path)])).is_err(), "{:?}", path);
+        }
+        assert!(check_pulled_paths(&manifest(vec![link("etc"), file("etc/passwd")])).is_err());
+    }
 }
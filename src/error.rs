@@ -29,6 +29,12 @@ pub enum FastSyncError {
 
     #[error("Config error: {0}")]
     Config(String),
+
+    #[error("Refusing to delete: {0}")]
+    DeleteRefused(String),
+
+    #[error("Sync completed with errors: {failed} files failed")]
+    PartialTransfer { failed: usize },
     
     #[error("WalkDir error: {0}")]
     WalkDir(#[from] walkdir::Error),
@@ -36,3 +42,71 @@ pub enum FastSyncError {
     #[error("Pattern error: {0}")]
     Pattern(#[from] globset::Error),
 }
+
+impl FastSyncError {
+    /// Whether the operation may succeed if retried, possibly over a fresh connection.
+    /// Only connection failures are; local I/O errors are not.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, FastSyncError::SshConnection(_))
+    }
+
+    /// An I/O error on an SSH channel (agent stream, SFTP file, command output).
+    /// The kinds that mean the connection is gone become `SshConnection`.
+    pub fn from_channel(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected | ErrorKind::TimedOut | ErrorKind::UnexpectedEof | ErrorKind::Interrupted => {
+                FastSyncError::SshConnection(format!("Channel I/O failed: {}", e))
+            }
+            _ => FastSyncError::Io(e),
+        }
+    }
+
+    /// Process exit code for this error, so scripts can tell failure classes apart:
+    ///
+    /// | Code | Meaning                                           |
+    /// |------|---------------------------------------------------|
+    /// | 1    | Other errors (local I/O, remote command, ...)     |
+    /// | 2    | Invalid configuration or pattern                  |
+    /// | 10   | Connection failed or dropped; retry later         |
+    /// | 11   | Authentication failed                             |
+    /// | 12   | Protocol error or agent version mismatch          |
+    /// | 23   | Partial transfer; some files failed               |
+    /// | 25   | Deletion refused by --max-delete or the user      |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FastSyncError::Config(_) | FastSyncError::Pattern(_) => 2,
+            FastSyncError::Authentication(_) => 11,
+            FastSyncError::Protocol(_) => 12,
+            FastSyncError::PartialTransfer { .. } => 23,
+            FastSyncError::DeleteRefused(_) => 25,
+            e if e.is_transient() => 10,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(FastSyncError::Config("bad".into()).exit_code(), 2);
+        assert_eq!(FastSyncError::SshConnection("down".into()).exit_code(), 10);
+        let reset = || std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(FastSyncError::from_channel(reset()).exit_code(), 10);
+        // The same kind from a local file is no reason to retry
+        assert_eq!(FastSyncError::Io(reset()).exit_code(), 1);
+        let eof = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(FastSyncError::Io(eof).exit_code(), 1);
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(FastSyncError::from_channel(denied).exit_code(), 1);
+        assert_eq!(FastSyncError::Authentication("denied".into()).exit_code(), 11);
+        assert_eq!(FastSyncError::Protocol("too old".into()).exit_code(), 12);
+        assert_eq!(FastSyncError::PartialTransfer { failed: 2 }.exit_code(), 23);
+        assert_eq!(FastSyncError::DeleteRefused("limit".into()).exit_code(), 25);
+        assert_eq!(FastSyncError::FileNotFound("a".into()).exit_code(), 1);
+    }
+}
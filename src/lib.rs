@@ -9,6 +9,7 @@ pub mod error;
 pub mod engine;
 pub mod protocol;
 pub mod server;
+pub mod report;
 
 pub use error::FastSyncError;
 pub type Result<T> = std::result::Result<T, FastSyncError>;
@@ -1,6 +1,6 @@
 use clap::Parser;
 use fastsync::config::Args;
-use fastsync::engine::SyncEngine;
+use fastsync::engine::{is_local_path, SyncEngine};
 use fastsync::server::Server;
 use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -17,17 +17,9 @@ fn main() -> anyhow::Result<()> {
         Level::INFO
     };
 
-    // If server mode, log to stderr only? Or file? 
-    // Standard stdout is used for protocol.
-    // So logging MUST go to stderr.
-    // FmtSubscriber defaults to stdout? No, it defaults to stdout.
-    // We need to change it to stderr for server mode.
-    
-    let writer = if args.server {
-        std::io::stderr
-    } else {
-        std::io::stderr // Actually CLI tools usually log to stderr to avoid corrupting pipes.
-    };
+    // Standard stdout is used for protocol in server mode, so logging MUST go to stderr.
+    // CLI tools usually log to stderr too, to avoid corrupting pipes.
+    let writer = std::io::stderr;
 
     let subscriber = FmtSubscriber::builder()
         .with_max_level(log_level)
@@ -39,10 +31,10 @@ fn main() -> anyhow::Result<()> {
 
     if args.server {
         info!("Starting server mode...");
-        let server = Server::new();
+        let mut server = Server::new();
         if let Err(e) = server.run() {
             error!("Server error: {}", e);
-            std::process::exit(1);
+            std::process::exit(e.exit_code());
         }
         return Ok(());
     }
@@ -54,22 +46,22 @@ fn main() -> anyhow::Result<()> {
 
     // Check if source exists
     if let Some(source) = &args.source {
-        if !source.exists() {
+        if is_local_path(&source.to_string_lossy()) && !source.exists() {
             error!("Source path does not exist: {:?}", source);
-            std::process::exit(1);
+            std::process::exit(2);
         }
     } else {
         // Should be handled by clap, but safe check
         if !args.server {
              error!("Source path required");
-             std::process::exit(1);
+             std::process::exit(2);
         }
     }
 
     let engine = SyncEngine::new(args);
     if let Err(e) = engine.run() {
         error!("Sync failed: {}", e);
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 
     Ok(())
@@ -1,14 +1,168 @@
-use serde::{Deserialize, Serialize};
-use crate::scanner::Manifest;
-use crate::delta::block_level::{FileSignature, FileDelta};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::DeserializeOwned;
+use crate::Result;
+use crate::apply::backup::Backup;
+use crate::apply::metadata::Metadata;
+use crate::apply::partial::Partial;
+use crate::scanner::{Manifest, ScanOptions};
+use crate::delta::block_level::{FileSignature, FileDelta, DeltaOp};
+use crate::util::hash::Checksum;
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Newest protocol version this build speaks.
+/// 10: `Hello` negotiates a version range and capabilities; frames carry request IDs.
+///     Agents from before it (fastsync 0.1.x) changed their layout without a version,
+///     so they can only be told apart by failing to decode.
+/// 11: manifests, scan options and metadata are `extensible`
+///
+/// New fields go into `extensible` structs with `#[serde(default)]` and need no new
+/// version. New requests do: append them to `Request` and send them only once
+/// `Session::version` has reached the version that introduced them.
+pub const PROTOCOL_VERSION: u32 = 11;
+/// Oldest protocol version this build still speaks. Protocol 10 sends the
+/// `extensible` structs in their plain layout, so this goes up to 11 as soon as
+/// any of them gains a field.
+pub const MIN_PROTOCOL_VERSION: u32 = 10;
+
+/// First version that encodes `extensible` fields as self-describing maps
+const EXTENSIBLE_VERSION: u32 = 11;
+
+/// Optional protocol features, exchanged as bit flags in `Hello`.
+/// Unknown bits from newer peers are simply dropped by the intersection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// zstd-compressed frames after the handshake
+    pub const COMPRESSION: Self = Self(1 << 0);
+    /// Whole-file BLAKE3 checksums in manifests (`--checksum`)
+    pub const CHECKSUMS: Self = Self(1 << 1);
+    /// `Request::Batch` of small whole files
+    pub const BATCH: Self = Self(1 << 2);
+    /// `Request::SetBackup` (--backup)
+    pub const BACKUP: Self = Self(1 << 3);
+    /// `Request::SetPartial` (--partial)
+    pub const PARTIAL: Self = Self(1 << 4);
+    /// `SetMetadata` for the file of the streamed delta in progress waits for its
+    /// `CommitDelta`, so it can be pipelined with the delta
+    pub const COMMIT_METADATA: Self = Self(1 << 5);
+
+    const NAMES: &'static [(Self, &'static str)] = &[
+        (Self::COMPRESSION, "compression"),
+        (Self::CHECKSUMS, "checksums"),
+        (Self::BATCH, "batch"),
+        (Self::BACKUP, "backup"),
+        (Self::PARTIAL, "partial"),
+        (Self::COMMIT_METADATA, "commit-metadata"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Everything this build implements
+    pub const fn supported() -> Self {
+        Self(Self::COMPRESSION.0 | Self::CHECKSUMS.0 | Self::BATCH.0 | Self::BACKUP.0 | Self::PARTIAL.0 | Self::COMMIT_METADATA.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES.iter()
+            .filter(|(cap, _)| self.contains(*cap))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Handshake sent by both sides. Its layout must never change, so that any two
+/// versions can at least tell each other they are incompatible; extend the
+/// protocol through versions and capability bits instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    /// fastsync release, for error messages
+    pub software: String,
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Capabilities,
+    /// zstd level the client asks for; ignored in the agent's reply
+    pub compress_level: Option<i32>,
+}
+
+impl Hello {
+    /// Our side of the handshake.
+    pub fn new(capabilities: Capabilities, compress_level: Option<i32>) -> Self {
+        Self {
+            software: env!("CARGO_PKG_VERSION").to_string(),
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities,
+            compress_level,
+        }
+    }
+}
+
+/// What client and agent agreed on in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub version: u32,
+    pub capabilities: Capabilities,
+    pub compress_level: Option<i32>,
+}
+
+/// Pick the highest common version and the common capabilities.
+/// Both sides run this on the same pair of `Hello`s and reach the same result.
+pub fn negotiate(client: &Hello, agent: &Hello) -> Result<Session> {
+    let version = client.max_version.min(agent.max_version);
+    if version < client.min_version.max(agent.min_version) {
+        return Err(crate::FastSyncError::Protocol(format!(
+            "No common protocol version: client fastsync {} speaks {}..={}, agent fastsync {} speaks {}..={}. \
+             Install matching fastsync versions on both sides.",
+            client.software, client.min_version, client.max_version,
+            agent.software, agent.min_version, agent.max_version,
+        )));
+    }
+
+    let capabilities = client.capabilities.intersection(agent.capabilities);
+    let compress_level = client.compress_level
+        .filter(|&level| capabilities.contains(Capabilities::COMPRESSION) && valid_compress_level(level));
+    Ok(Session { version, capabilities, compress_level })
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    /// Handshake / Check capability
-    Hello { version: u32 },
+    /// Handshake; must stay the first variant so every version can decode it
+    Hello(Hello),
     
-    /// Get file list from remote
-    GetManifest { path: String },
+    /// Get file list from remote, filtered by the client's exclude rules
+    GetManifest {
+        path: String,
+        #[serde(with = "extensible")]
+        options: ScanOptions,
+    },
     
     /// Get block signatures for a file (for delta calculation)
     GetSignature { path: String, block_size: usize },
@@ -19,11 +173,60 @@ pub enum Request {
     /// Create directory
     MkDir { path: String, mode: u32 },
 
-    /// Set file metadata (mtime/permissions) after transfer
-    SetMetadata { path: String, mtime: i64, mode: u32 },
+    /// Set file metadata (mtime, permissions, ownership) after transfer.
+    /// For the file of the streamed delta in progress it is applied at `CommitDelta`.
+    SetMetadata {
+        path: String,
+        #[serde(with = "extensible")]
+        metadata: Metadata,
+    },
     
     /// Delete file/dir
     Delete { path: String },
+    
+    /// Compute delta of a remote file against a local signature (pull mode).
+    /// Answered with the first `DeltaChunk`; request more with `NextDeltaChunk`.
+    GetDelta { path: String, signature: FileSignature },
+    
+    /// Next chunk of the delta started by `GetDelta`. Answered with `Ok` once the
+    /// last chunk has gone out, so requests pipelined past the end aren't errors.
+    NextDeltaChunk,
+    
+    /// Start a streamed delta for a file, followed by `DeltaChunk`s and `CommitDelta`
+    BeginDelta { path: String, block_size: usize },
+    
+    /// Ops for the streamed delta in progress
+    DeltaChunk { ops: Vec<DeltaOp> },
+    
+    /// Finish the streamed delta and move the file into place.
+    /// `checksum` is the BLAKE3 of the whole new file; the agent refuses to commit on mismatch.
+    CommitDelta { final_size: u64, checksum: Checksum },
+
+    /// Write many small files whole, in one round trip. Answered with `Response::Batch`.
+    Batch { files: Vec<BatchFile> },
+
+    /// From now on, move files aside before they are replaced or deleted (`None` turns it off)
+    SetBackup { backup: Option<Backup> },
+
+    /// From now on, keep interrupted streamed deltas and use them as the basis of the next one
+    SetPartial { partial: Option<Partial> },
+
+    /// Create a symlink at `path` pointing to `target`, replacing what is there
+    Symlink { path: String, target: String },
+
+    /// Make `path` a hard link to the existing file `target`, replacing what is there
+    HardLink { path: String, target: String },
+}
+
+/// A small file sent whole in a `Batch`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchFile {
+    pub path: String,
+    pub data: Vec<u8>,
+    #[serde(with = "extensible")]
+    pub metadata: Metadata,
+    /// BLAKE3 of `data`, checked before the file is moved into place
+    pub checksum: Checksum,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,15 +234,225 @@ pub enum Response {
     /// Generic Ack
     Ok,
     
-    /// Handshake Ack
-    Hello { version: u32 },
+    /// Handshake reply with the agent's own range and capabilities
+    Hello(Hello),
     
     /// Return Manifest
-    Manifest(Manifest),
+    Manifest(#[serde(with = "extensible")] Manifest),
     
     /// Return Signature
     Signature(FileSignature),
     
     /// Error occurred
     Error { message: String },
+    
+    /// Return one chunk of a delta. `checksum` (BLAKE3 of the whole file) is set on the last one.
+    DeltaChunk { ops: Vec<DeltaOp>, checksum: Option<Checksum> },
+    
+    /// The written file didn't match the expected checksum
+    ChecksumMismatch { path: String },
+
+    /// Per-file outcome of a `Batch`, in request order: `None` on success, else the error
+    Batch { errors: Vec<Option<String>> },
+}
+
+/// Whether the local zstd accepts `level`.
+pub fn valid_compress_level(level: i32) -> bool {
+    zstd::compression_level_range().contains(&level)
+}
+
+/// How frames are encoded. The handshake always uses the plain default;
+/// afterwards it follows what the session negotiated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCodec {
+    pub compress_level: Option<i32>,
+    /// Negotiated protocol version; `None` during the handshake, whose frames
+    /// carry no request ID
+    pub version: Option<u32>,
+}
+
+impl FrameCodec {
+    pub fn for_session(session: Option<&Session>) -> Self {
+        session.map(|s| Self { compress_level: s.compress_level, version: Some(s.version) }).unwrap_or_default()
+    }
+
+    /// Frames carry a u32 request ID after the length
+    pub fn request_ids(&self) -> bool {
+        self.version.is_some()
+    }
+}
+
+thread_local! {
+    /// Version of the frame being encoded or decoded on this thread, for `extensible`
+    static FRAME_VERSION: Cell<u32> = const { Cell::new(PROTOCOL_VERSION) };
+}
+
+/// Run `f` with `extensible` fields following `codec`'s version.
+fn in_frame<T>(codec: FrameCodec, f: impl FnOnce() -> T) -> T {
+    let previous = FRAME_VERSION.replace(codec.version.unwrap_or(PROTOCOL_VERSION));
+    let result = f();
+    FRAME_VERSION.set(previous);
+    result
+}
+
+/// Serde adapter for structs that keep gaining fields (manifests, scan options,
+/// metadata). From protocol 11 they travel as self-describing MessagePack maps:
+/// older peers skip fields they don't know, newer ones default the missing ones.
+/// Protocol 10 gets the plain bincode layout.
+mod extensible {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if FRAME_VERSION.get() < EXTENSIBLE_VERSION {
+            return value.serialize(serializer);
+        }
+        let bytes = rmp_serde::to_vec_named(value).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<T, D::Error> {
+        if FRAME_VERSION.get() < EXTENSIBLE_VERSION {
+            return T::deserialize(deserializer);
+        }
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        rmp_serde::from_slice(&bytes).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Write one frame: u32 big-endian length, the u32 request `id` if the codec uses them,
+/// then the bincode payload, zstd-compressed when the codec says so.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, codec: FrameCodec, id: u32, msg: &T) -> Result<()> {
+    let mut data = in_frame(codec, || bincode::serialize(msg))
+        .map_err(|e| crate::FastSyncError::Protocol(format!("Serialize error: {}", e)))?;
+    if let Some(level) = codec.compress_level {
+        data = zstd::bulk::compress(&data, level)?;
+    }
+
+    let len = u32::try_from(data.len())
+        .map_err(|_| crate::FastSyncError::Protocol(format!("Frame too large: {} bytes", data.len())))?;
+    writer.write_all(&len.to_be_bytes())?;
+    if codec.request_ids() {
+        writer.write_all(&id.to_be_bytes())?;
+    }
+    writer.write_all(&data)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read one frame written by `write_frame`, with its request ID (0 if the codec has none).
+/// Returns `None` if the stream is closed before a new frame starts.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R, codec: FrameCodec) -> Result<Option<(u32, T)>> {
+    let mut len_buf = [0u8; 4];
+    if let Err(e) = reader.read_exact(&mut len_buf) {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e.into());
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+
+    let mut id = 0;
+    if codec.request_ids() {
+        let mut id_buf = [0u8; 4];
+        reader.read_exact(&mut id_buf)?;
+        id = u32::from_be_bytes(id_buf);
+    }
+
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data)?;
+    if codec.compress_level.is_some() {
+        data = zstd::stream::decode_all(&data[..])?;
+    }
+
+    let msg = in_frame(codec, || bincode::deserialize(&data))
+        .map_err(|e| crate::FastSyncError::Protocol(format!("Deserialize error: {}", e)))?;
+    Ok(Some((id, msg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// bincode size of a default `FileEntry` in the protocol 10 layout
+    const PROTOCOL_10_ENTRY_LEN: usize = 73;
+
+    fn hello(min_version: u32, max_version: u32, capabilities: Capabilities) -> Hello {
+        Hello { min_version, max_version, capabilities, ..Hello::new(Capabilities::empty(), Some(3)) }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let client = hello(10, 11, Capabilities::supported());
+        let agent = hello(10, 10, Capabilities::CHECKSUMS.union(Capabilities(1 << 40)));
+        let session = negotiate(&client, &agent).unwrap();
+        assert_eq!(session.version, 10);
+        assert_eq!(session.capabilities, Capabilities::CHECKSUMS);
+        assert_eq!(session.compress_level, None);
+        assert_eq!(session.capabilities.to_string(), "checksums");
+
+        let agent = hello(11, 12, Capabilities::supported());
+        let session = negotiate(&client, &agent).unwrap();
+        assert_eq!((session.version, session.compress_level), (11, Some(3)));
+
+        let newer_agent = hello(12, 13, Capabilities::supported());
+        match negotiate(&client, &newer_agent) {
+            Err(crate::FastSyncError::Protocol(msg)) => assert!(msg.contains("No common protocol version")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_extensible_fields() {
+        use crate::scanner::FileEntry;
+
+        // A manifest from a newer peer, with fields this build doesn't know
+        #[derive(Serialize)]
+        struct NewerEntry { path: String, size: u64, added_later: String }
+        #[derive(Serialize)]
+        struct NewerManifest { root_path: String, entries: Vec<NewerEntry>, added_later: u32 }
+        #[derive(Serialize)]
+        struct Sent(#[serde(with = "extensible")] NewerManifest);
+        #[derive(Deserialize)]
+        struct Received(#[serde(with = "extensible")] Manifest);
+
+        let codec = FrameCodec { compress_level: None, version: Some(EXTENSIBLE_VERSION) };
+        let newer = NewerManifest {
+            root_path: "/srv".into(),
+            entries: vec![NewerEntry { path: "a.txt".into(), size: 3, added_later: "x".into() }],
+            added_later: 1,
+        };
+        let mut buf = Vec::new();
+        write_frame(&mut buf, codec, 1, &Sent(newer)).unwrap();
+        let (_, Received(manifest)) = read_frame(&mut &buf[..], codec).unwrap().unwrap();
+        assert_eq!(manifest.root_path, "/srv");
+        assert_eq!((manifest.entries[0].path.as_str(), manifest.entries[0].size), ("a.txt", 3));
+        assert!(manifest.entries[0].is_file());
+
+        // Protocol 10 peers get the plain layout
+        let codec = FrameCodec { compress_level: None, version: Some(10) };
+        let mut buf = Vec::new();
+        write_frame(&mut buf, codec, 2, &Response::Manifest(manifest)).unwrap();
+        assert!(matches!(read_frame(&mut &buf[..], codec).unwrap(), Some((2, Response::Manifest(m))) if m.entries.len() == 1));
+        // Changes once a field is added; protocol 10 can't decode that, so raise
+        // MIN_PROTOCOL_VERSION to 11 along with updating this
+        assert_eq!(bincode::serialize(&FileEntry::default()).unwrap().len(), PROTOCOL_10_ENTRY_LEN);
+    }
+
+    #[test]
+    fn test_compressed_frame_roundtrip() {
+        let ops = vec![DeltaOp::Data { data: b"text text text text text text text text ".repeat(100) }];
+        let codec = FrameCodec { compress_level: Some(3), version: Some(PROTOCOL_VERSION) };
+        let mut plain = Vec::new();
+        write_frame(&mut plain, FrameCodec::default(), 0, &Request::DeltaChunk { ops: ops.clone() }).unwrap();
+        let mut packed = Vec::new();
+        write_frame(&mut packed, codec, 7, &Request::DeltaChunk { ops }).unwrap();
+        assert!(packed.len() < plain.len() / 10);
+
+        let mut reader = &packed[..];
+        match read_frame(&mut reader, codec).unwrap() {
+            Some((7, Request::DeltaChunk { ops })) => assert_eq!(ops.len(), 1),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+        assert!(read_frame::<_, Request>(&mut reader, codec).unwrap().is_none());
+    }
 }
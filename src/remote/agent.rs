@@ -1,69 +1,186 @@
 use crate::Result;
-use crate::scanner::{Manifest, Scanner};
+use crate::apply::backup::Backup;
+use crate::apply::metadata::Metadata;
+use crate::apply::partial::Partial;
+use crate::scanner::{Manifest, Scanner, ScanOptions};
 use crate::transport::ssh::SshConnection;
-use crate::protocol::{Request, Response};
-use crate::delta::block_level::{FileSignature, FileDelta};
+use crate::protocol::{negotiate, read_frame, write_frame, BatchFile, Capabilities, FrameCodec, Hello, Request, Response, Session};
+use crate::delta::block_level::{FileSignature, FileDelta, DeltaOp, DeltaStats, DeltaStream, DELTA_CHUNK_SIZE};
 use ssh2::Channel;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::path::Path;
-use std::io::{Read, Write};
-use tracing::debug;
+use std::io::Read;
+use crate::util::hash::Checksum;
+use tracing::{debug, warn};
+
+/// Command that starts the agent on the remote
+pub const AGENT_COMMAND: &str = "fastsync --server";
+
+/// Most requests `pipeline` keeps in flight. Answers pile up on the agent side
+/// while we are still sending, so this keeps the channel from deadlocking.
+const MAX_IN_FLIGHT: usize = 16;
 
 pub struct AgentRemote {
     channel: Channel,
+    /// IDs of the requests sent but not yet answered, oldest first
+    in_flight: VecDeque<u32>,
+    next_id: u32,
+    /// Agreed on in the handshake
+    session: Option<Session>,
+    /// The frame stream failed; nothing more can be exchanged
+    broken: bool,
 }
 
 impl AgentRemote {
-    pub fn new(conn: &SshConnection, remote_cmd: &str) -> Result<Self> {
+    /// Start the agent and handshake; `compress_level` requests zstd-compressed frames.
+    pub fn new(conn: &SshConnection, remote_cmd: &str, compress_level: Option<i32>) -> Result<Self> {
         let mut channel = conn.open_channel()?;
         
         channel.exec(remote_cmd)
              .map_err(|e| crate::FastSyncError::RemoteCommand(format!("Failed to exec agent: {}", e)))?;
         
         // Handshake
-        let mut agent = Self { channel };
-        agent.handshake()?;
+        let mut agent = Self { channel, in_flight: VecDeque::new(), next_id: 0, session: None, broken: false };
+        agent.handshake(compress_level)?;
         
         Ok(agent)
     }
 
-    fn handshake(&mut self) -> Result<()> {
-        self.send_request(Request::Hello { version: 1 })?;
-        match self.read_response()? {
-            Response::Hello { version } => {
-                debug!("Remote agent version: {}", version);
-                Ok(())
-            },
-            resp => Err(crate::FastSyncError::Protocol(format!("Unexpected handshake response: {:?}", resp))),
+    fn handshake(&mut self, compress_level: Option<i32>) -> Result<()> {
+        let ours = Hello::new(Capabilities::supported(), compress_level);
+        self.send_request(Request::Hello(ours.clone()))?;
+        let theirs = match self.read_response() {
+            Ok(Response::Hello(hello)) => hello,
+            Ok(resp) => return Err(crate::FastSyncError::Protocol(format!("Unexpected handshake response: {:?}", resp))),
+            // Agents from before version negotiation can't even answer in a form we decode
+            Err(crate::FastSyncError::Protocol(e)) => return Err(crate::FastSyncError::Protocol(format!(
+                "Remote agent speaks an incompatible protocol ({}); install fastsync {} on the remote", e, ours.software))),
+            Err(e) => return Err(e),
+        };
+
+        let session = negotiate(&ours, &theirs)?;
+        debug!("Remote agent fastsync {}, protocol {}, capabilities: {}", theirs.software, session.version, session.capabilities);
+        if compress_level.is_some() && session.compress_level.is_none() {
+            warn!("Remote agent doesn't support compression; sending uncompressed");
         }
+        self.session = Some(session);
+        Ok(())
     }
 
-    fn send_request(&mut self, req: Request) -> Result<()> {
-        let data = bincode::serialize(&req)
-            .map_err(|e| crate::FastSyncError::Protocol(format!("Serialize error: {}", e)))?;
-        
-        let len = data.len() as u32;
-        self.channel.write_all(&len.to_be_bytes()).map_err(crate::FastSyncError::Io)?;
-        self.channel.write_all(&data).map_err(crate::FastSyncError::Io)?;
-        self.channel.flush().map_err(crate::FastSyncError::Io)?;
+    /// Capabilities both sides agreed on.
+    pub fn capabilities(&self) -> Capabilities {
+        self.session.map(|s| s.capabilities).unwrap_or_default()
+    }
+
+    /// Fail with a `Protocol` error unless the agent agreed to all of `required`.
+    pub fn require(&self, required: Capabilities) -> Result<()> {
+        let missing = required.difference(self.capabilities());
+        if missing != Capabilities::empty() {
+            return Err(crate::FastSyncError::Protocol(format!("Remote agent doesn't support: {}; upgrade fastsync on the remote", missing)));
+        }
         Ok(())
     }
 
+    fn send_request(&mut self, req: Request) -> Result<()> {
+        let codec = FrameCodec::for_session(self.session.as_ref());
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let result = write_frame(&mut self.channel, codec, id, &req).map_err(channel_error);
+        self.broken |= result.is_err();
+        self.in_flight.push_back(id);
+        result
+    }
+
+    /// Read the answer to the oldest request still in flight.
     fn read_response(&mut self) -> Result<Response> {
-        let mut len_buf = [0u8; 4];
-        self.channel.read_exact(&mut len_buf).map_err(crate::FastSyncError::Io)?;
-        let len = u32::from_be_bytes(len_buf) as usize;
+        let codec = FrameCodec::for_session(self.session.as_ref());
+        let expected = self.in_flight.pop_front();
+        let resp = read_frame(&mut self.channel, codec)
+            .map_err(channel_error)
+            .and_then(|frame| match frame {
+                None => Err(crate::FastSyncError::Protocol("Agent closed the connection".into())),
+                Some((id, resp)) if !codec.request_ids() || Some(id) == expected => Ok(resp),
+                Some((id, _)) => Err(crate::FastSyncError::Protocol(format!("Response for request {} while waiting for {:?}", id, expected))),
+            })
+            .inspect_err(|_| self.broken = true)?;
+        
+        match resp {
+            Response::Error { message } => Err(crate::FastSyncError::RemoteCommand(message)),
+            Response::ChecksumMismatch { path } => Err(crate::FastSyncError::ChecksumMismatch { path: path.into() }),
+            resp => Ok(resp),
+        }
+    }
 
-        let mut buf = vec![0u8; len];
-        self.channel.read_exact(&mut buf).map_err(crate::FastSyncError::Io)?;
+    /// Send `requests` without waiting for each answer, keeping up to `MAX_IN_FLIGHT`
+    /// outstanding. `on_response` gets each answer with its request's tag, in order.
+    pub fn pipeline<T, I, F>(&mut self, requests: I, mut on_response: F) -> Result<()>
+    where
+        I: IntoIterator<Item = (T, Request)>,
+        F: FnMut(T, Result<Response>),
+    {
+        let mut tags = VecDeque::new();
+        for (tag, req) in requests {
+            if tags.len() >= MAX_IN_FLIGHT {
+                self.pipeline_receive(&mut tags, &mut on_response)?;
+            }
+            self.send_request(req)?;
+            tags.push_back(tag);
+        }
+        while !tags.is_empty() {
+            self.pipeline_receive(&mut tags, &mut on_response)?;
+        }
+        Ok(())
+    }
 
-        let resp = bincode::deserialize(&buf)
-            .map_err(|e| crate::FastSyncError::Protocol(format!("Deserialize error: {}", e)))?;
-        
-        if let Response::Error { message } = &resp {
-            return Err(crate::FastSyncError::RemoteCommand(message.clone()));
+    fn pipeline_receive<T, F>(&mut self, tags: &mut VecDeque<T>, on_response: &mut F) -> Result<()>
+    where
+        F: FnMut(T, Result<Response>),
+    {
+        let tag = tags.pop_front().expect("response without a request in flight");
+        match self.read_response() {
+            // The stream is gone; the remaining answers will never come
+            Err(e) if self.broken => Err(e),
+            resp => {
+                on_response(tag, resp);
+                Ok(())
+            }
         }
-        
-        Ok(resp)
+    }
+
+    /// Write small files whole, one `Batch` per item of `batches`, pipelined.
+    /// `on_done` gets every file's path and outcome.
+    pub fn write_batches<I, F>(&mut self, batches: I, mut on_done: F) -> Result<()>
+    where
+        I: IntoIterator<Item = Vec<BatchFile>>,
+        F: FnMut(&str, Result<()>),
+    {
+        let requests = batches.into_iter().map(|files| {
+            let paths: Vec<String> = files.iter().map(|f| f.path.clone()).collect();
+            (paths, Request::Batch { files })
+        });
+
+        self.pipeline(requests, |paths, resp| match resp {
+            Ok(Response::Batch { errors }) if errors.len() == paths.len() => {
+                for (path, error) in paths.iter().zip(errors) {
+                    on_done(path, error.map_or(Ok(()), |message| Err(crate::FastSyncError::RemoteCommand(message))));
+                }
+            }
+            other => {
+                let message = match other {
+                    Err(e) => e.to_string(),
+                    Ok(resp) => format!("Unexpected response for Batch: {:?}", resp),
+                };
+                for path in &paths {
+                    on_done(path, Err(crate::FastSyncError::RemoteCommand(message.clone())));
+                }
+            }
+        })
+    }
+
+    /// Whether the connection to the agent failed and it must be replaced.
+    pub fn is_broken(&self) -> bool {
+        self.broken
     }
 
     pub fn get_signature(&mut self, path: &str, block_size: usize) -> Result<FileSignature> {
@@ -74,6 +191,41 @@ impl AgentRemote {
         }
     }
 
+    /// Pull a delta of the remote file against a local signature, chunk by chunk.
+    /// Returns the BLAKE3 checksum of the remote file.
+    ///
+    /// Up to `MAX_IN_FLIGHT` chunks are asked for ahead, so the agent never waits
+    /// a round trip between them. It answers the requests past the last chunk with
+    /// errors, which are read and dropped.
+    pub fn get_delta_stream<F>(&mut self, path: &str, signature: FileSignature, mut on_chunk: F) -> Result<Checksum>
+    where
+        F: FnMut(&[DeltaOp]) -> Result<()>,
+    {
+        self.send_request(Request::GetDelta { path: path.to_string(), signature })?;
+        let result = loop {
+            while self.in_flight.len() < MAX_IN_FLIGHT {
+                self.send_request(Request::NextDeltaChunk)?;
+            }
+            match self.read_response() {
+                Ok(Response::DeltaChunk { ops, checksum }) => {
+                    if let Err(e) = on_chunk(&ops) {
+                        break Err(e);
+                    }
+                    if let Some(checksum) = checksum {
+                        break Ok(checksum);
+                    }
+                },
+                Ok(resp) => break Err(crate::FastSyncError::Protocol(format!("Unexpected response for GetDelta: {:?}", resp))),
+                Err(e) => break Err(e),
+            }
+        };
+        // Requests pipelined past the last chunk are answered with Ok (an error from older agents)
+        while !self.in_flight.is_empty() && !self.broken {
+            let _ = self.read_response();
+        }
+        result
+    }
+
     pub fn apply_delta(&mut self, path: &str, delta: FileDelta) -> Result<()> {
         self.send_request(Request::ApplyDelta { path: path.to_string(), delta })?;
         match self.read_response()? {
@@ -81,14 +233,173 @@ impl AgentRemote {
             resp => Err(crate::FastSyncError::Protocol(format!("Unexpected response for ApplyDelta: {:?}", resp))),
         }
     }
+
+    /// Stream the delta of `reader` against `signature` to the agent in bounded chunks,
+    /// so neither side holds the whole file in memory, and apply `metadata` to the result.
+    ///
+    /// The requests are pipelined: chunks go out back to back and the answers are
+    /// checked as the window fills. After the first failure no more chunks are read.
+    /// Agents with `COMMIT_METADATA` take the metadata in the same pipeline.
+    pub fn apply_delta_stream<R: Read>(&mut self, path: &str, reader: R, signature: FileSignature, metadata: &Metadata) -> Result<DeltaStats> {
+        let mut begin = vec![("BeginDelta", Request::BeginDelta { path: path.to_string(), block_size: signature.block_size })];
+        let commit_metadata = self.capabilities().contains(Capabilities::COMMIT_METADATA);
+        if commit_metadata {
+            begin.push(("SetMetadata", Request::SetMetadata { path: path.to_string(), metadata: metadata.clone() }));
+        }
+        let mut stream = Some(DeltaStream::new(reader, signature));
+        let mut stats = DeltaStats::default();
+        let mut read_error = None;
+        let failed = Cell::new(false);
+
+        let chunks = std::iter::from_fn(|| {
+            if failed.get() {
+                return None;
+            }
+            match stream.as_mut()?.next_chunk(DELTA_CHUNK_SIZE) {
+                Ok(ops) if ops.is_empty() => {
+                    let stream = stream.take()?;
+                    Some(("CommitDelta", Request::CommitDelta { final_size: stream.bytes_read(), checksum: stream.checksum() }))
+                }
+                Ok(ops) => {
+                    stats.add_ops(&ops);
+                    Some(("DeltaChunk", Request::DeltaChunk { ops }))
+                }
+                Err(e) => {
+                    read_error = Some(e);
+                    None
+                }
+            }
+        });
+        let requests = begin.into_iter().chain(chunks);
+
+        let mut error = None;
+        self.pipeline(requests, |what, resp| {
+            let resp_error = match resp {
+                Ok(Response::Ok) => return,
+                Ok(resp) => crate::FastSyncError::Protocol(format!("Unexpected response for {}: {:?}", what, resp)),
+                Err(e) => e,
+            };
+            // Later requests only fail for want of a delta in progress
+            if error.is_none() {
+                error = Some(resp_error);
+                failed.set(true);
+            }
+        })?;
+
+        if let Some(e) = read_error {
+            return Err(e.into());
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        if !commit_metadata {
+            self.set_metadata(path, metadata)?;
+        }
+        Ok(stats)
+    }
+
+    /// Delete remote files and directory trees, pipelined.
+    /// Every path is attempted; failures are reported together.
+    pub fn delete_paths(&mut self, paths: &[String]) -> Result<()> {
+        let failures: Vec<String> = paths.iter()
+            .zip(self.for_each_path(paths, |path| Request::Delete { path })?)
+            .filter_map(|(path, result)| result.err().map(|e| format!("{}: {}", path, e)))
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(crate::FastSyncError::RemoteCommand(format!("Failed to delete {} paths: {}", failures.len(), failures.join("; "))))
+        }
+    }
+
+    /// Create remote directories with their missing parents, pipelined.
+    /// Returns each one's outcome.
+    pub fn make_dirs(&mut self, paths: &[String]) -> Result<Vec<Result<()>>> {
+        self.for_each_path(paths, |path| Request::MkDir { path, mode: 0o755 })
+    }
+
+    /// Pipeline one request per path, each answered with `Ok`. The error is only
+    /// for the connection; each path's own outcome is in the list.
+    fn for_each_path<F>(&mut self, paths: &[String], request: F) -> Result<Vec<Result<()>>>
+    where
+        F: Fn(String) -> Request,
+    {
+        let mut outcomes = Vec::with_capacity(paths.len());
+        let requests = paths.iter().map(|path| ((), request(path.clone())));
+        self.pipeline(requests, |_, resp| outcomes.push(match resp {
+            Ok(Response::Ok) => Ok(()),
+            Ok(resp) => Err(crate::FastSyncError::Protocol(format!("unexpected response {:?}", resp))),
+            Err(e) => Err(e),
+        }))?;
+        Ok(outcomes)
+    }
+
+    /// Have the agent move files aside before replacing or deleting them.
+    pub fn set_backup(&mut self, backup: Option<Backup>) -> Result<()> {
+        if backup.is_some() {
+            self.require(Capabilities::BACKUP)?;
+        }
+        self.send_request(Request::SetBackup { backup })?;
+        self.expect_ok("SetBackup")
+    }
+
+    /// Have the agent keep interrupted streamed deltas and resume from them.
+    pub fn set_partial(&mut self, partial: Option<Partial>) -> Result<()> {
+        if partial.is_some() {
+            self.require(Capabilities::PARTIAL)?;
+        }
+        self.send_request(Request::SetPartial { partial })?;
+        self.expect_ok("SetPartial")
+    }
+
+    /// Apply mtime, permissions and ownership to a remote file or directory.
+    pub fn set_metadata(&mut self, path: &str, metadata: &Metadata) -> Result<()> {
+        self.send_request(Request::SetMetadata { path: path.to_string(), metadata: metadata.clone() })?;
+        self.expect_ok("SetMetadata")
+    }
+
+    /// Create a remote symlink at `path` pointing to `target`.
+    pub fn symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        self.send_request(Request::Symlink { path: path.to_string(), target: target.to_string() })?;
+        self.expect_ok("Symlink")
+    }
+
+    /// Make the remote `path` a hard link to the remote file `target`.
+    pub fn hard_link(&mut self, path: &str, target: &str) -> Result<()> {
+        self.send_request(Request::HardLink { path: path.to_string(), target: target.to_string() })?;
+        self.expect_ok("HardLink")
+    }
+
+    fn expect_ok(&mut self, what: &str) -> Result<()> {
+        match self.read_response()? {
+            Response::Ok => Ok(()),
+            resp => Err(crate::FastSyncError::Protocol(format!("Unexpected response for {}: {:?}", what, resp))),
+        }
+    }
 }
 
-impl Scanner for AgentRemote {
-    fn scan(&mut self, path: &Path) -> Result<Manifest> {
-        self.send_request(Request::GetManifest { path: path.to_string_lossy().to_string() })?;
+/// I/O on the agent's channel fails because the connection did
+fn channel_error(e: crate::FastSyncError) -> crate::FastSyncError {
+    match e {
+        crate::FastSyncError::Io(e) => crate::FastSyncError::from_channel(e),
+        e => e,
+    }
+}
+
+impl AgentRemote {
+    /// Scan the remote path, collecting whatever `options` asks for (e.g. checksums).
+    pub fn scan_with(&mut self, path: &Path, options: ScanOptions) -> Result<Manifest> {
+        self.send_request(Request::GetManifest { path: path.to_string_lossy().to_string(), options })?;
         match self.read_response()? {
             Response::Manifest(m) => Ok(m),
             resp => Err(crate::FastSyncError::Protocol(format!("Unexpected response for GetManifest: {:?}", resp))),
         }
     }
 }
+
+impl Scanner for AgentRemote {
+    fn scan(&mut self, path: &Path) -> Result<Manifest> {
+        self.scan_with(path, ScanOptions::default())
+    }
+}
@@ -1,7 +1,10 @@
 use crate::Result;
+use crate::apply::backup::Backup;
 use crate::scanner::{Manifest, FileEntry, Scanner};
-use crate::transport::Transport;
+use crate::transport::{is_windows_remote_path, Transport};
+use crate::util::hash::{hash_file, sha256_file, to_hex};
 use std::path::{Path, PathBuf};
+use std::sync::Once;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct AgentlessRemote<'a> {
@@ -36,7 +39,7 @@ impl<'a> AgentlessRemote<'a> {
             full_entry.path = rel_path_str;
             entries.push(full_entry.clone());
 
-            if full_entry.is_dir {
+            if full_entry.is_dir() {
                 // Recursively scan subdirectories
                 // Note: We might want to handle errors gracefully (e.g. permission denied) 
                 // but for now we propagate.
@@ -64,3 +67,156 @@ impl<'a> Scanner for AgentlessRemote<'a> {
         })
     }
 }
+
+/// Whole-file checksum as reported by a tool on the remote side
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemoteChecksum {
+    Blake3(String),
+    Sha256(String),
+}
+
+impl RemoteChecksum {
+    /// Compare against the content of a local file.
+    pub fn matches(&self, local: &Path) -> std::io::Result<bool> {
+        Ok(match self {
+            RemoteChecksum::Blake3(hex) => to_hex(&hash_file(local)?) == *hex,
+            RemoteChecksum::Sha256(hex) => to_hex(&sha256_file(local)?) == *hex,
+        })
+    }
+}
+
+/// Hash a remote file with whatever the remote has: `b3sum`, then `sha256sum`
+/// (PowerShell `Get-FileHash` on Windows). `None` if no tool is available.
+pub fn remote_checksum(conn: &dyn Transport, remote: &str, is_windows: bool) -> Result<Option<RemoteChecksum>> {
+    if is_windows {
+        let cmd = format!(
+            "powershell -NoProfile -NonInteractive -Command \"(Get-FileHash -Algorithm SHA256 -LiteralPath '{}').Hash\"",
+            remote.replace('\'', "''")
+        );
+        let out = conn.exec(&cmd)?;
+        return Ok(Some(RemoteChecksum::Sha256(out.trim().to_ascii_lowercase())));
+    }
+
+    let path = remote.replace('\'', "'\\''");
+    let cmd = format!(
+        "if command -v b3sum >/dev/null 2>&1; then echo b3 $(b3sum --no-names -- '{0}'); \
+         elif command -v sha256sum >/dev/null 2>&1; then echo sha256 $(sha256sum -- '{0}'); \
+         else echo none; fi",
+        path
+    );
+    let out = conn.exec(&cmd)?;
+    let mut parts = out.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("b3"), Some(hex)) => Ok(Some(RemoteChecksum::Blake3(hex.to_ascii_lowercase()))),
+        (Some("sha256"), Some(hex)) => Ok(Some(RemoteChecksum::Sha256(hex.to_ascii_lowercase()))),
+        (Some("none"), _) => Ok(None),
+        _ => Err(crate::FastSyncError::RemoteCommand(format!("Failed to hash remote file {}: {}", remote, out.trim()))),
+    }
+}
+
+/// Check that a remote file matches a local one after transfer.
+/// Fails with `ChecksumMismatch`; passes if the remote can't hash files, warning once.
+pub fn verify_checksum(conn: &dyn Transport, local: &Path, remote: &str, is_windows: bool) -> Result<()> {
+    match remote_checksum(conn, remote, is_windows)? {
+        Some(checksum) => {
+            if checksum.matches(local)? {
+                Ok(())
+            } else {
+                Err(crate::FastSyncError::ChecksumMismatch { path: PathBuf::from(remote) })
+            }
+        }
+        None => {
+            static WARNED: Once = Once::new();
+            WARNED.call_once(|| tracing::warn!("The remote has neither b3sum nor sha256sum; transfers are not verified"));
+            tracing::debug!("No checksum tool on remote, skipping verification of {}", remote);
+            Ok(())
+        }
+    }
+}
+
+/// Make the remote `path` a hard link to the remote file `target`, replacing what is
+/// there. SFTP has no hard links (libssh2 lacks `hardlink@openssh.com`), so this runs
+/// `ln` (PowerShell `New-Item` on Windows).
+pub fn hard_link(conn: &dyn Transport, target: &str, path: &str, is_windows: bool) -> Result<()> {
+    let cmd = if is_windows {
+        format!(
+            "powershell -NoProfile -NonInteractive -Command \"Remove-Item -Force -ErrorAction SilentlyContinue -LiteralPath '{0}'; \
+             New-Item -ItemType HardLink -Path '{0}' -Target '{1}' | Out-Null\"",
+            path.replace('\'', "''"),
+            target.replace('\'', "''")
+        )
+    } else {
+        format!("ln -f -- '{}' '{}'", target.replace('\'', "'\\''"), path.replace('\'', "'\\''"))
+    };
+    conn.exec(&cmd)?;
+    Ok(())
+}
+
+/// Longest remote command `delete_paths` builds; cmd.exe stops at 8191 characters.
+const MAX_COMMAND_LEN: usize = 8000;
+
+/// Delete remote files and directory trees with as few commands as the
+/// command-line limit allows, instead of one exec per path.
+pub fn delete_paths(conn: &dyn Transport, paths: &[String], is_windows: bool) -> Result<()> {
+    let (prefix, suffix) = if is_windows {
+        ("powershell -NoProfile -NonInteractive -Command \"Remove-Item -Force -Recurse -ErrorAction Stop -LiteralPath ", "\"")
+    } else {
+        ("rm -rf --", "")
+    };
+
+    let mut args: Vec<String> = Vec::new();
+    let mut len = 0;
+    for path in paths {
+        let arg = if is_windows {
+            format!("'{}'", path.replace('\'', "''"))
+        } else {
+            format!(" '{}'", path.replace('\'', "'\\''"))
+        };
+        if !args.is_empty() && prefix.len() + len + arg.len() + suffix.len() > MAX_COMMAND_LEN {
+            run_delete(conn, prefix, &args, suffix, is_windows)?;
+            args.clear();
+            len = 0;
+        }
+        len += arg.len() + 1;
+        args.push(arg);
+    }
+    if !args.is_empty() {
+        run_delete(conn, prefix, &args, suffix, is_windows)?;
+    }
+    Ok(())
+}
+
+fn run_delete(conn: &dyn Transport, prefix: &str, args: &[String], suffix: &str, is_windows: bool) -> Result<()> {
+    // PowerShell takes a comma-separated list of paths, rm a space-separated one
+    let args = if is_windows { args.join(",") } else { args.concat() };
+    conn.exec(&format!("{}{}{}", prefix, args, suffix))?;
+    Ok(())
+}
+
+/// Move remote paths aside with SFTP renames before they are replaced or deleted.
+/// Every path is attempted; failures are reported together.
+pub fn backup_paths(conn: &dyn Transport, backup: &Backup, paths: &[PathBuf]) -> Result<()> {
+    let mut failures = Vec::new();
+    for path in paths {
+        let result = backup.path_for(path).and_then(|target| {
+            if let Some(parent) = target.parent() {
+                conn.create_dir_all(parent)?;
+            }
+            conn.rename(path, &target).or_else(|_| {
+                // An older backup that can't be renamed over (a directory, say) is removed first
+                let target = target.to_string_lossy().into_owned();
+                delete_paths(conn, std::slice::from_ref(&target), is_windows_remote_path(&target))?;
+                conn.rename(path, Path::new(&target))
+            })
+        });
+        if let Err(e) = result {
+            failures.push(format!("{}: {}", path.display(), e));
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(crate::FastSyncError::RemoteCommand(format!("Failed to back up {} paths: {}", failures.len(), failures.join("; "))))
+    }
+}
@@ -1,2 +1,3 @@
 pub mod agent;
-pub mod agentless;
\ No newline at end of file
+pub mod agentless;
+pub mod pool;
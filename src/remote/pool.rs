@@ -0,0 +1,157 @@
+use crate::Result;
+use crate::apply::backup::Backup;
+use crate::apply::partial::Partial;
+use crate::remote::agent::{AgentRemote, AGENT_COMMAND};
+use crate::transport::ssh::{SshConfig, SshConnection};
+use std::sync::{Condvar, Mutex};
+use tracing::{debug, warn};
+
+/// An SSH connection with its own running agent.
+pub struct AgentSession {
+    pub conn: SshConnection,
+    pub agent: AgentRemote,
+}
+
+impl AgentSession {
+    pub fn connect(config: &SshConfig, compress_level: Option<i32>) -> Result<Self> {
+        let conn = SshConnection::connect(config)?;
+        let agent = AgentRemote::new(&conn, AGENT_COMMAND, compress_level)?;
+        Ok(Self { conn, agent })
+    }
+}
+
+/// Agent sessions shared by the worker threads for the whole run.
+/// At most `size` sessions are open at once; a session that breaks is dropped
+/// and a new one is connected on the next checkout.
+pub struct AgentPool {
+    config: SshConfig,
+    compress_level: Option<i32>,
+    /// Sent to every new session
+    backup: Option<Backup>,
+    partial: Option<Partial>,
+    size: usize,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<AgentSession>,
+    open: usize,
+}
+
+impl AgentPool {
+    pub fn new(config: SshConfig, size: usize, compress_level: Option<i32>) -> Self {
+        Self {
+            config,
+            compress_level,
+            backup: None,
+            partial: None,
+            size: size.max(1),
+            state: Mutex::new(PoolState { idle: Vec::new(), open: 0 }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Have every session back up the files it replaces or deletes.
+    pub fn with_backup(mut self, backup: Option<Backup>) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    /// Have every session keep interrupted uploads for the next run.
+    pub fn with_partial(mut self, partial: Option<Partial>) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// Run `work` on a pooled session. If the session breaks, the work is retried
+    /// on a freshly connected one, backing off per the retry policy. A reused session
+    /// that died while idle gets one free retry.
+    pub fn with_session<T, F>(&self, mut work: F) -> Result<T>
+    where
+        F: FnMut(&mut AgentSession) -> Result<T>,
+    {
+        let (mut session, mut reused) = self.checkout()?;
+        let mut attempt = 0;
+        loop {
+            let result = work(&mut session);
+            if !is_broken(&session, &result) {
+                self.release(Some(session));
+                return result;
+            }
+
+            let retry = session.agent.is_broken() || result.as_ref().is_err_and(|e| e.is_transient());
+            warn!("Dropping broken agent session");
+            drop(session);
+            self.release(None);
+            if !retry {
+                return result;
+            }
+            if reused {
+                debug!("Pooled agent session failed, reconnecting");
+            } else {
+                let policy = self.config.retry;
+                if attempt >= policy.retries {
+                    return result;
+                }
+                let wait = policy.backoff(attempt);
+                attempt += 1;
+                warn!("Agent session failed, reconnecting in {:?} ({}/{})", wait, attempt, policy.retries);
+                std::thread::sleep(wait);
+            }
+            (session, reused) = self.checkout_new()?;
+        }
+    }
+
+    /// Take an idle session, or connect a new one if fewer than `size` are open.
+    fn checkout(&self) -> Result<(AgentSession, bool)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(session) = state.idle.pop() {
+                return Ok((session, true));
+            }
+            if state.open < self.size {
+                drop(state);
+                return self.checkout_new();
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    fn checkout_new(&self) -> Result<(AgentSession, bool)> {
+        self.state.lock().unwrap().open += 1;
+        let connected = AgentSession::connect(&self.config, self.compress_level).and_then(|mut session| {
+            if self.backup.is_some() {
+                session.agent.set_backup(self.backup.clone())?;
+            }
+            if self.partial.is_some() {
+                session.agent.set_partial(self.partial.clone())?;
+            }
+            Ok(session)
+        });
+        match connected {
+            Ok(session) => Ok((session, false)),
+            Err(e) => {
+                self.release(None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Return a session to the pool, or give up its slot when it is `None`.
+    fn release(&self, session: Option<AgentSession>) {
+        let mut state = self.state.lock().unwrap();
+        match session {
+            Some(session) => state.idle.push(session),
+            None => state.open -= 1,
+        }
+        self.available.notify_one();
+    }
+}
+
+/// Whether the session can't be used again after `result`.
+/// Per-file failures (reported by the agent, or local I/O) keep it alive.
+fn is_broken<T>(session: &AgentSession, result: &Result<T>) -> bool {
+    session.agent.is_broken()
+        || matches!(result, Err(crate::FastSyncError::Protocol(_) | crate::FastSyncError::SshConnection(_)))
+}
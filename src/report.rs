@@ -0,0 +1,248 @@
+use crate::Result;
+use crate::delta::block_level::DeltaStats;
+use crate::delta::file_level::{Change, SyncAction};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// What a sync run did (--report-json, --itemize-changes).
+#[derive(Debug, Serialize)]
+pub struct SyncReport {
+    pub source: String,
+    pub destination: String,
+    pub dry_run: bool,
+    /// Unix time the run started
+    pub started_at: u64,
+    pub duration_secs: f64,
+    /// Every planned action, with its outcome
+    pub files: Vec<FileReport>,
+    pub totals: Totals,
+    /// Why the run failed, if it did
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub changes: Vec<Change>,
+    /// The action was carried out successfully
+    pub done: bool,
+    /// Literal bytes crossed the link; copied bytes were reused from the old file
+    #[serde(flatten)]
+    pub stats: DeltaStats,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Totals {
+    /// Entries whose content was sent, or that were linked
+    pub transferred: usize,
+    /// Entries only whose metadata was updated in place
+    pub updated: usize,
+    pub deleted: usize,
+    pub failed: usize,
+    /// Size of the files whose content was sent
+    pub total_size: u64,
+    /// Bytes sent: the literal data of deltas and whole files
+    pub literal_bytes: u64,
+    pub copied_bytes: u64,
+}
+
+/// Collects the outcome of every action while a run is in progress.
+pub struct Report {
+    source: String,
+    destination: String,
+    dry_run: bool,
+    started: Instant,
+    started_at: u64,
+    state: Mutex<ReportState>,
+}
+
+#[derive(Default)]
+struct ReportState {
+    files: Vec<FileReport>,
+    /// path -> index into `files`
+    index: HashMap<String, usize>,
+}
+
+impl Report {
+    pub fn new(source: &str, destination: &str, dry_run: bool) -> Self {
+        Self {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            dry_run,
+            started: Instant::now(),
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            state: Mutex::new(ReportState::default()),
+        }
+    }
+
+    /// Register the actions a run is about to carry out.
+    pub fn plan(&self, actions: &[SyncAction]) {
+        let mut state = self.state.lock().unwrap();
+        for action in actions {
+            let file = match action {
+                SyncAction::Upload(entry, changes) | SyncAction::Metadata(entry, changes) => FileReport {
+                    path: entry.path.clone(),
+                    is_dir: entry.is_dir(),
+                    size: entry.size,
+                    changes: changes.clone(),
+                    done: false,
+                    stats: DeltaStats::default(),
+                    error: None,
+                },
+                SyncAction::HardLink(entry, _) => FileReport {
+                    path: entry.path.clone(),
+                    is_dir: false,
+                    size: entry.size,
+                    changes: vec![Change::HardLink],
+                    done: false,
+                    stats: DeltaStats::default(),
+                    error: None,
+                },
+                SyncAction::Delete(path) => FileReport {
+                    path: path.clone(),
+                    is_dir: false,
+                    size: 0,
+                    changes: vec![Change::Deleted],
+                    done: false,
+                    stats: DeltaStats::default(),
+                    error: None,
+                },
+            };
+            let index = state.files.len();
+            state.index.insert(file.path.clone(), index);
+            state.files.push(file);
+        }
+    }
+
+    /// Record the outcome of the planned action for `path`.
+    pub fn record(&self, path: &str, result: &Result<DeltaStats>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(&index) = state.index.get(path) else {
+            return;
+        };
+        let file = &mut state.files[index];
+        match result {
+            Ok(stats) => {
+                file.done = true;
+                file.stats = *stats;
+                file.error = None;
+            }
+            Err(e) => file.error = Some(e.to_string()),
+        }
+    }
+
+    /// "path: error" for every action that failed so far.
+    pub fn errors(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.files.iter()
+            .filter_map(|f| f.error.as_ref().map(|e| format!("{}: {}", f.path, e)))
+            .collect()
+    }
+
+    pub fn finish(&self, error: Option<&crate::FastSyncError>) -> SyncReport {
+        let files = self.state.lock().unwrap().files.clone();
+        let mut totals = Totals::default();
+        for file in &files {
+            if file.error.is_some() {
+                totals.failed += 1;
+            } else if !file.done {
+                continue;
+            } else if file.changes.contains(&Change::Deleted) {
+                totals.deleted += 1;
+            } else if file.changes.iter().all(|c| c.is_metadata()) {
+                totals.updated += 1;
+            } else {
+                totals.transferred += 1;
+                if !file.is_dir && !file.changes.contains(&Change::HardLink) {
+                    totals.total_size += file.size;
+                }
+                totals.literal_bytes += file.stats.literal_bytes;
+                totals.copied_bytes += file.stats.copied_bytes;
+            }
+        }
+
+        SyncReport {
+            source: self.source.clone(),
+            destination: self.destination.clone(),
+            dry_run: self.dry_run,
+            started_at: self.started_at,
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            files,
+            totals,
+            error: error.map(|e| e.to_string()),
+        }
+    }
+}
+
+impl SyncReport {
+    /// One line per changed entry: its changes, then its path (directories end in `/`).
+    /// Lists what was done, or in a dry run what would be.
+    pub fn itemize(&self) -> Vec<String> {
+        self.files.iter()
+            .filter(|f| f.done || self.dry_run)
+            .map(|f| {
+                let changes: Vec<&str> = f.changes.iter().map(|c| c.as_str()).collect();
+                format!("{:<16} {}{}", changes.join(","), f.path, if f.is_dir { "/" } else { "" })
+            })
+            .collect()
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(file, self).map_err(|e| crate::FastSyncError::Io(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::FileEntry;
+
+    #[test]
+    fn test_report_totals_and_itemize() {
+        let entry = |path: &str, size| FileEntry { path: path.into(), size, ..Default::default() };
+        let report = Report::new("src", "dst", false);
+        report.plan(&[
+            SyncAction::Upload(entry("new.txt", 100), vec![Change::New]),
+            SyncAction::Upload(entry("big.bin", 1000), vec![Change::Size, Change::Mtime]),
+            SyncAction::Upload(entry("locked.txt", 5), vec![Change::Mtime]),
+            SyncAction::Metadata(entry("script.sh", 4000), vec![Change::Perms]),
+            SyncAction::HardLink(entry("big.link", 1000), "big.bin".into()),
+            SyncAction::Delete("old".into()),
+        ]);
+
+        report.record("new.txt", &Ok(DeltaStats::literal(100)));
+        report.record("big.bin", &Ok(DeltaStats { literal_bytes: 200, copied_bytes: 800 }));
+        report.record("locked.txt", &Err(crate::FastSyncError::Config("denied".into())));
+        report.record("script.sh", &Ok(DeltaStats::default()));
+        report.record("big.link", &Ok(DeltaStats::default()));
+        report.record("old", &Ok(DeltaStats::default()));
+        assert_eq!(report.errors(), vec!["locked.txt: Config error: denied"]);
+
+        let summary = report.finish(None);
+        assert_eq!(summary.totals.transferred, 3);
+        assert_eq!(summary.totals.updated, 1);
+        assert_eq!(summary.totals.deleted, 1);
+        assert_eq!(summary.totals.failed, 1);
+        assert_eq!(summary.totals.total_size, 1100);
+        assert_eq!(summary.totals.literal_bytes, 300);
+        assert_eq!(summary.totals.copied_bytes, 800);
+        assert_eq!(summary.itemize(), vec![
+            format!("{:<16} new.txt", "new"),
+            format!("{:<16} big.bin", "size,mtime"),
+            format!("{:<16} script.sh", "perms"),
+            format!("{:<16} big.link", "hardlink"),
+            format!("{:<16} old", "deleted"),
+        ]);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["files"][1]["literal_bytes"], 200);
+        assert_eq!(json["files"][1]["changes"][0], "size");
+    }
+}
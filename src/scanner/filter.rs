@@ -0,0 +1,60 @@
+use crate::Result;
+use crate::scanner::Manifest;
+use ignore::overrides::{Override, OverrideBuilder};
+use std::path::Path;
+
+/// Build gitignore-style exclude overrides rooted at `root`.
+pub fn build_overrides(root: &Path, excludes: &[String]) -> Result<Override> {
+    let mut overrides = OverrideBuilder::new(root);
+    for pattern in excludes {
+        // To ignore a pattern, we add it prefixed with "!" in OverrideBuilder
+        overrides.add(&format!("!{}", pattern)).map_err(|e| crate::FastSyncError::Config(e.to_string()))?;
+    }
+    overrides.build().map_err(|e| crate::FastSyncError::Config(e.to_string()))
+}
+
+/// Exclude rules applied to manifest paths after the fact,
+/// e.g. to a remote manifest that was scanned without them.
+pub struct ExcludeFilter {
+    overrides: Override,
+}
+
+impl ExcludeFilter {
+    pub fn new(excludes: &[String]) -> Result<Self> {
+        Ok(Self { overrides: build_overrides(Path::new(""), excludes)? })
+    }
+
+    /// A path is excluded if it or any of its parent directories matches.
+    pub fn is_excluded(&self, path: &str, is_dir: bool) -> bool {
+        let path = Path::new(path);
+        if self.overrides.matched(path, is_dir).is_ignore() {
+            return true;
+        }
+        path.ancestors()
+            .skip(1)
+            .filter(|p| !p.as_os_str().is_empty())
+            .any(|p| self.overrides.matched(p, true).is_ignore())
+    }
+
+    /// Drop excluded entries from a manifest.
+    pub fn apply(&self, mut manifest: Manifest) -> Manifest {
+        manifest.entries.retain(|e| !self.is_excluded(&e.path, e.is_dir()));
+        manifest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_filter() {
+        let filter = ExcludeFilter::new(&["*.log".into(), "node_modules".into()]).unwrap();
+
+        assert!(filter.is_excluded("app.log", false));
+        assert!(filter.is_excluded("sub/app.log", false));
+        assert!(filter.is_excluded("node_modules", true));
+        assert!(filter.is_excluded("node_modules/pkg/index.js", false));
+        assert!(!filter.is_excluded("src/main.rs", false));
+    }
+}
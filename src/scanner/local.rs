@@ -1,4 +1,9 @@
-use crate::scanner::{Manifest, FileEntry, Scanner};
+use crate::scanner::{Manifest, FileEntry, FileKind, Scanner, ScanOptions};
+use crate::util::hash::hash_file;
+use crate::util::ids;
+use crate::util::xattr;
+use rayon::prelude::*;
+use crate::scanner::filter::build_overrides;
 use crate::Result;
 use ignore::WalkBuilder;
 use std::path::Path;
@@ -8,12 +13,12 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use std::os::unix::fs::MetadataExt;
 
 pub struct LocalScanner {
-    excludes: Vec<String>,
+    options: ScanOptions,
 }
 
 impl LocalScanner {
-    pub fn new(excludes: Vec<String>) -> Self {
-        Self { excludes }
+    pub fn new(options: ScanOptions) -> Self {
+        Self { options }
     }
 }
 
@@ -27,19 +32,13 @@ impl Scanner for LocalScanner {
         // Configure WalkBuilder
         let mut builder = WalkBuilder::new(&root);
         builder.hidden(false); 
-        builder.git_ignore(true);
-        builder.follow_links(false); // <--- 重要：不跟随符号链接，防止误判
+        builder.git_ignore(self.options.gitignore);
+        // Links are recorded as links unless --copy-links asks to follow them
+        builder.follow_links(self.options.copy_links);
         
         // Add custom overrides
-        if !self.excludes.is_empty() {
-             let mut overrides = ignore::overrides::OverrideBuilder::new(&root);
-             for pattern in &self.excludes {
-                 // To ignore a pattern, we add it prefixed with "!" in OverrideBuilder
-                 overrides.add(&format!("!{}", pattern)).map_err(|e| crate::FastSyncError::Config(e.to_string()))?;
-             }
-             if let Ok(ov) = overrides.build() {
-                 builder.overrides(ov);
-             }
+        if !self.options.excludes.is_empty() {
+             builder.overrides(build_overrides(&root, &self.options.excludes)?);
         }
 
         for result in builder.build() {
@@ -64,14 +63,37 @@ impl Scanner for LocalScanner {
                          }
                      };
                      
-                     let (mtime, mode) = get_metadata_platform(&metadata);
+                     let (mtime, mtime_nsec, mode, uid, gid) = get_metadata_platform(&metadata);
+                     let (dev, inode, nlink) = get_inode_platform(&metadata);
+                     let file_type = metadata.file_type();
+                     let (kind, link_target) = if file_type.is_symlink() {
+                         match std::fs::read_link(p) {
+                             Ok(target) => (FileKind::Symlink, Some(target.to_string_lossy().to_string())),
+                             Err(e) => {
+                                 tracing::warn!("Failed to read link {:?}: {}", p, e);
+                                 continue;
+                             }
+                         }
+                     } else if file_type.is_dir() {
+                         (FileKind::Dir, None)
+                     } else {
+                         (FileKind::File, None)
+                     };
 
                      entries.push(FileEntry {
                          path: path_str,
                          size: metadata.len(),
                          mtime,
+                         mtime_nsec,
                          mode,
-                         is_dir: metadata.is_dir(),
+                         kind,
+                         link_target,
+                         dev,
+                         inode,
+                         nlink,
+                         uid,
+                         gid,
+                         ..Default::default()
                      });
                 }
                 Err(err) => {
@@ -80,6 +102,33 @@ impl Scanner for LocalScanner {
             }
         }
 
+        if self.options.checksum {
+            // Hash file contents in parallel
+            entries.par_iter_mut()
+                .filter(|e| e.is_file())
+                .for_each(|e| match hash_file(&root.join(&e.path)) {
+                    Ok(checksum) => e.checksum = Some(checksum),
+                    Err(err) => tracing::warn!("Failed to hash {}: {}", e.path, err),
+                });
+        }
+
+        if !self.options.xattrs.is_empty() {
+            let scope = self.options.xattrs;
+            entries.par_iter_mut()
+                .filter(|e| !e.is_symlink())
+                .for_each(|e| match xattr::read(&root.join(&e.path), scope) {
+                    Ok(values) => e.xattrs = Some(values),
+                    Err(err) => tracing::warn!("Failed to read xattrs of {}: {}", e.path, err),
+                });
+        }
+
+        if self.options.owner_names {
+            for entry in &mut entries {
+                entry.user = ids::user_name(entry.uid);
+                entry.group = ids::group_name(entry.gid);
+            }
+        }
+
         Ok(Manifest {
             generated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
             root_path: root.to_string_lossy().to_string(),
@@ -89,19 +138,29 @@ impl Scanner for LocalScanner {
 }
 
 #[cfg(unix)]
-fn get_metadata_platform(metadata: &std::fs::Metadata) -> (i64, u32) {
-    (metadata.mtime(), metadata.mode())
+fn get_metadata_platform(metadata: &std::fs::Metadata) -> (i64, u32, u32, u32, u32) {
+    (metadata.mtime(), metadata.mtime_nsec() as u32, metadata.mode(), metadata.uid(), metadata.gid())
+}
+
+#[cfg(unix)]
+fn get_inode_platform(metadata: &std::fs::Metadata) -> (u64, u64, u64) {
+    (metadata.dev(), metadata.ino(), metadata.nlink())
+}
+
+#[cfg(not(unix))]
+fn get_inode_platform(_metadata: &std::fs::Metadata) -> (u64, u64, u64) {
+    (0, 0, 0)
 }
 
 #[cfg(not(unix))]
-fn get_metadata_platform(metadata: &std::fs::Metadata) -> (i64, u32) {
-    let mtime = metadata.modified()
+fn get_metadata_platform(metadata: &std::fs::Metadata) -> (i64, u32, u32, u32, u32) {
+    let (mtime, mtime_nsec) = metadata.modified()
         .ok()
         .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
-        .map(|d| d.as_secs() as i64)
-        .unwrap_or(0);
+        .map(|d| (d.as_secs() as i64, d.subsec_nanos()))
+        .unwrap_or((0, 0));
     
     // Synthesize mode for non-unix
     let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
-    (mtime, mode)
+    (mtime, mtime_nsec, mode, 0, 0)
 }
@@ -1,13 +1,26 @@
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use crate::Result;
+use crate::util::hash::Checksum;
+use crate::util::xattr::{XattrScope, Xattrs};
 
 pub mod local;
+pub mod filter;
 
 pub use local::LocalScanner;
 
+/// What a manifest entry is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+}
+
 /// File metadata
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct FileEntry {
     /// Relative path (using / as separator)
     pub path: String,
@@ -15,14 +28,103 @@ pub struct FileEntry {
     pub size: u64,
     /// Modification time (Unix timestamp, seconds)
     pub mtime: i64,
+    /// Sub-second part of `mtime` in nanoseconds; 0 where only whole seconds are known
+    pub mtime_nsec: u32,
     /// File permissions (Unix mode, e.g., 0o644)
     pub mode: u32,
-    /// Is directory
-    pub is_dir: bool,
+    pub kind: FileKind,
+    /// Where a symlink points, as stored in the link
+    pub link_target: Option<String>,
+    /// Owner and group ids (0 where the platform has none)
+    pub uid: u32,
+    pub gid: u32,
+    /// Owner and group names, only filled in when scanning for --owner/--group
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Device, inode and link count, to find hard links (-H); 0 where unknown
+    pub dev: u64,
+    pub inode: u64,
+    pub nlink: u64,
+    /// BLAKE3 of the content, only filled in when scanning for --checksum
+    pub checksum: Option<Checksum>,
+    /// Extended attributes and ACLs of files and dirs, only filled in when scanning for --xattrs/--acls
+    pub xattrs: Option<Xattrs>,
 }
 
-/// Directory manifest
+impl FileEntry {
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+
+    /// Identity shared by all names of a file with more than one hard link
+    pub fn hard_link_id(&self) -> Option<(u64, u64)> {
+        (self.is_file() && self.nlink > 1 && self.inode != 0).then_some((self.dev, self.inode))
+    }
+
+    /// Whether a symlink's target stays inside the scanned tree (--safe-links):
+    /// relative, and never climbing above the root.
+    pub fn link_stays_inside(&self) -> bool {
+        let Some(target) = &self.link_target else { return true };
+        if target.starts_with(['/', '\\']) || Path::new(target).is_absolute() {
+            return false;
+        }
+        // Directories below the root that hold the link
+        let mut depth = self.path.matches('/').count();
+        for part in target.split(['/', '\\']) {
+            match part {
+                "" | "." => {}
+                ".." if depth == 0 => return false,
+                ".." => depth -= 1,
+                _ => depth += 1,
+            }
+        }
+        true
+    }
+}
+
+/// What a scan collects and which paths it skips.
+/// Sent to the agent so both sides of a sync filter the same way.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanOptions {
+    /// Hash every file's content (for --checksum)
+    pub checksum: bool,
+    /// Exclude patterns (gitignore syntax), relative to the scan root
+    pub excludes: Vec<String>,
+    /// Skip what .gitignore files inside the tree list
+    pub gitignore: bool,
+    /// Look up user and group names, so ids can be mapped by name (--owner/--group)
+    pub owner_names: bool,
+    /// Follow symlinks and record what they point to instead (--copy-links)
+    pub copy_links: bool,
+    /// Which extended attributes to collect (--xattrs/--acls)
+    pub xattrs: XattrScope,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            checksum: false,
+            excludes: Vec::new(),
+            gitignore: true,
+            owner_names: false,
+            copy_links: false,
+            xattrs: XattrScope::default(),
+        }
+    }
+}
+
+/// Directory manifest
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Manifest {
     /// Manifest generation time
     pub generated_at: i64,
@@ -37,3 +139,24 @@ pub trait Scanner {
     /// Scan directory and return manifest
     fn scan(&mut self, path: &Path) -> Result<Manifest>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_stays_inside() {
+        let link = |path: &str, target: &str| FileEntry {
+            path: path.into(),
+            kind: FileKind::Symlink,
+            link_target: Some(target.into()),
+            ..Default::default()
+        };
+        assert!(link("current", "v1.2.3").link_stays_inside());
+        assert!(link("a/b/link", "../../c").link_stays_inside());
+        assert!(link("a/link", "./b/../c").link_stays_inside());
+        assert!(!link("a/link", "../../c").link_stays_inside());
+        assert!(!link("link", "/etc/passwd").link_stays_inside());
+        assert!(!link("link", "x/../../y").link_stays_inside());
+    }
+}
@@ -1,92 +1,182 @@
-use crate::protocol::{Request, Response};
+use crate::protocol::{negotiate, read_frame, write_frame, BatchFile, Capabilities, FrameCodec, Hello, Request, Response, Session};
 use crate::scanner::{Scanner, LocalScanner};
-use crate::delta::block_level::{compute_signature, apply_delta, DEFAULT_BLOCK_SIZE};
+use crate::delta::block_level::{compute_signature, apply_delta, apply_ops, DeltaStream, FileSignature, ReadSeek, DELTA_CHUNK_SIZE, MAX_BLOCK_SIZE};
+use crate::apply::backup::Backup;
+use crate::apply::metadata::Metadata;
+use crate::apply::partial::Partial;
+use crate::apply::local::{create_symlink, hard_link, remove_path, temp_path, writable_parent};
+use crate::util::hash::{Checksum, HashWriter};
 use crate::Result;
-use std::io::{self, Read, Write, Seek};
-use std::path::Path;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write, Seek};
+use std::path::{Path, PathBuf};
 use tracing::{info, error};
 
-trait ReadSeek: Read + Seek {}
-impl<T: Read + Seek> ReadSeek for T {}
-
 pub struct Server {
     // Current working directory or restrict to a root?
     // For now we assume paths in requests are absolute or relative to CWD.
     // Safety: we should prevent .. escaping if possible, but for MVP we rely on OS.
+
+    /// Streamed delta being written (BeginDelta .. CommitDelta)
+    incoming: Option<IncomingDelta>,
+    /// Streamed delta being read (GetDelta .. last DeltaChunk)
+    outgoing: Option<DeltaStream<BufReader<File>>>,
+    /// Set once the last chunk of `outgoing` has gone out, until the next `GetDelta`
+    outgoing_ended: bool,
+    /// Set once `Hello` has been negotiated
+    session: Option<Session>,
+    /// Set by `SetBackup`
+    backup: Option<Backup>,
+    /// Set by `SetPartial`
+    partial: Option<Partial>,
+}
+
+struct IncomingDelta {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    old_file: Option<File>,
+    tmp_file: HashWriter<BufWriter<File>>,
+    block_size: usize,
+    /// Applied to the new file before it moves into place
+    metadata: Option<Metadata>,
+}
+
+impl IncomingDelta {
+    /// Drop an unfinished delta. With `partial`, what has arrived is kept as the
+    /// partial file, unless an earlier attempt already got further.
+    fn abort(self, partial: Option<&Partial>) {
+        let IncomingDelta { path, tmp_path, old_file, tmp_file, .. } = self;
+        drop(old_file);
+        if let Some(partial) = partial {
+            let partial_path = partial.path_for(&path);
+            let kept = (|| -> io::Result<bool> {
+                let mut tmp_file = tmp_file.into_inner().into_inner().map_err(|e| e.into_error())?;
+                let written = tmp_file.stream_position()?;
+                let previous = std::fs::metadata(&partial_path).map(|m| m.len()).unwrap_or(0);
+                if written <= previous {
+                    return Ok(false);
+                }
+                tmp_file.sync_all()?;
+                drop(tmp_file);
+                if let Some(parent) = partial_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::rename(&tmp_path, &partial_path)?;
+                Ok(true)
+            })();
+            if matches!(kept, Ok(true)) {
+                return;
+            }
+        } else {
+            drop(tmp_file);
+        }
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Server {
     pub fn new() -> Self {
-        Self {}
+        Self { incoming: None, outgoing: None, outgoing_ended: false, session: None, backup: None, partial: None }
     }
 
-    pub fn run(&self) -> Result<()> {
+    pub fn run(&mut self) -> Result<()> {
         let stdin = io::stdin();
-        let mut stdin_lock = stdin.lock();
         let stdout = io::stdout();
-        let mut stdout_lock = stdout.lock();
+        self.serve(stdin.lock(), stdout.lock())
+    }
 
+    /// Serve framed requests from `input` until it is closed.
+    pub fn serve<R: Read, W: Write>(&mut self, mut stdin_lock: R, mut stdout_lock: W) -> Result<()> {
         loop {
-            // Read Request length (4 bytes u32 big endian)
-            let mut len_buf = [0u8; 4];
-            if let Err(e) = stdin_lock.read_exact(&mut len_buf) {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    // Stream closed cleanly
+            // Frames follow what `Hello` negotiated; the handshake itself is plain
+            let codec = FrameCodec::for_session(self.session.as_ref());
+            let (id, req): (u32, Request) = match read_frame(&mut stdin_lock, codec) {
+                Ok(Some(frame)) => frame,
+                // Stream closed cleanly
+                Ok(None) => {
+                    self.abort_incoming();
                     return Ok(());
                 }
-                error!("Server read error: {}", e);
-                return Err(crate::FastSyncError::Io(e));
-            }
-            let len = u32::from_be_bytes(len_buf) as usize;
-
-            // Read Payload
-            let mut buf = vec![0u8; len];
-            stdin_lock.read_exact(&mut buf).map_err(crate::FastSyncError::Io)?;
-
-            // Deserialize
-            let req: Request = bincode::deserialize(&buf)
-                .map_err(|e| crate::FastSyncError::Protocol(format!("Deserialize failed: {}", e)))?;
-
-            // Process
-            let resp = self.handle_request(req);
-
-            // Serialize Response
-            let resp_bytes = bincode::serialize(&resp)
-                .map_err(|e| crate::FastSyncError::Protocol(format!("Serialize failed: {}", e)))?;
-            
-            // Write Response length + Payload
-            let resp_len = resp_bytes.len() as u32;
-            stdout_lock.write_all(&resp_len.to_be_bytes()).map_err(crate::FastSyncError::Io)?;
-            stdout_lock.write_all(&resp_bytes).map_err(crate::FastSyncError::Io)?;
-            stdout_lock.flush().map_err(crate::FastSyncError::Io)?;
+                Err(e) => {
+                    error!("Server read error: {}", e);
+                    self.abort_incoming();
+                    return Err(e);
+                }
+            };
+
+            let is_hello = matches!(req, Request::Hello(_));
+            let resp = if is_hello || self.session.is_some() {
+                self.handle_request(req)
+            } else {
+                Response::Error { message: "Handshake required before any other request".into() }
+            };
+            // Requests are answered in order, each tagged with its ID
+            write_frame(&mut stdout_lock, codec, id, &resp)?;
+
+            // The client reports the details; there is nothing useful left to serve
+            if is_hello && self.session.is_none() {
+                return Err(crate::FastSyncError::Protocol("Handshake failed".into()));
+            }
         }
     }
 
-    fn handle_request(&self, req: Request) -> Response {
+    /// Answer `Hello` with our own range and capabilities, and remember what was agreed.
+    fn handshake(&mut self, client: Hello) -> Response {
+        let ours = Hello::new(Capabilities::supported(), None);
+        self.session = match negotiate(&client, &ours) {
+            Ok(session) => {
+                info!("Client fastsync {} connected, protocol {}, capabilities: {}",
+                    client.software, session.version, session.capabilities);
+                Some(session)
+            }
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        };
+        Response::Hello(ours)
+    }
+
+    fn handle_request(&mut self, req: Request) -> Response {
         match req {
-            Request::Hello { version } => {
-                info!("Client connected, version {}", version);
-                Response::Hello { version: 1 }
-            },
-            Request::GetManifest { path } => {
-                let mut scanner = LocalScanner::new(vec![]); // No excludes for now?
+            Request::Hello(hello) => self.handshake(hello),
+            Request::GetManifest { path, options } => {
+                let mut scanner = LocalScanner::new(options);
                 match scanner.scan(Path::new(&path)) {
                     Ok(manifest) => Response::Manifest(manifest),
                     Err(e) => Response::Error { message: e.to_string() },
                 }
             },
             Request::GetSignature { path, block_size } => {
-                match std::fs::File::open(&path) {
+                if block_size == 0 {
+                    return invalid_block_size(block_size);
+                }
+                // The signature carries the size used, which the delta then follows
+                let block_size = block_size.min(MAX_BLOCK_SIZE);
+                match std::fs::File::open(self.basis_path(Path::new(&path))) {
                     Ok(mut f) => {
                         match compute_signature(&mut f, block_size) {
                             Ok(sig) => Response::Signature(sig),
                             Err(e) => Response::Error { message: e.to_string() },
                         }
                     },
+                    // Nothing to build on; the delta will be all data
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        Response::Signature(FileSignature { blocks: vec![], block_size, file_size: 0 })
+                    }
                     Err(e) => Response::Error { message: e.to_string() },
                 }
             },
             Request::ApplyDelta { path, delta } => {
+                if !valid_block_size(delta.block_size) {
+                    return invalid_block_size(delta.block_size);
+                }
                 let path_obj = Path::new(&path);
                 
                 // Open old file or use empty cursor if new file
@@ -98,14 +188,26 @@ impl Server {
                     None => &mut empty_cursor,
                 };
 
-                let tmp_path = path_obj.with_extension("tmp.rrsync");
+                writable_parent(path_obj);
+                let tmp_path = temp_path(path_obj);
                 let mut tmp_file = match std::fs::File::create(&tmp_path) {
-                    Ok(f) => f,
+                    Ok(f) => HashWriter::new(f),
                     Err(e) => return Response::Error { message: format!("Failed to create temp file: {}", e) },
                 };
 
-                match apply_delta(old_reader, &delta, &mut tmp_file, DEFAULT_BLOCK_SIZE) {
+                match apply_delta(old_reader, &delta, &mut tmp_file, delta.block_size) {
                     Ok(_) => {
+                        drop(old_file_opt);
+                        if tmp_file.checksum() != delta.checksum {
+                            drop(tmp_file);
+                            let _ = std::fs::remove_file(&tmp_path);
+                            return Response::ChecksumMismatch { path };
+                        }
+                        drop(tmp_file);
+                        if let Err(e) = self.save_backup(path_obj) {
+                            let _ = std::fs::remove_file(&tmp_path);
+                            return Response::Error { message: format!("Backup failed: {}", e) };
+                        }
                         if let Err(e) = std::fs::rename(&tmp_path, path_obj) {
                              return Response::Error { message: format!("Failed to rename temp file: {}", e) };
                         }
@@ -118,43 +220,249 @@ impl Server {
                 }
             },
             Request::MkDir { path, mode: _ } => {
+                 writable_parent(Path::new(&path));
                  match std::fs::create_dir_all(&path) {
                      Ok(_) => Response::Ok,
                      Err(e) => Response::Error { message: e.to_string() },
                  }
             },
-            Request::SetMetadata { path, mtime: _, mode: _ } => {
-                 // Setting mode is platform specific (unix), we skip for basic impl or use set_permissions?
-                 // For now, at least set mtime.
-                 let _f = match std::fs::File::open(&path) {
-                     Ok(f) => f,
-                     Err(e) => return Response::Error { message: e.to_string() },
-                 };
-                 
-                 // mtime setting requires filetime crate or similar. 
-                 // std doesn't support setting mtime easily yet.
-                 // We can use `libc` on linux or `winapi` on windows, OR just use the `filetime` crate.
-                 // We didn't add `filetime` crate. 
-                 // We can execute "touch"? But server might not have touch.
-                 // For MVP, we might skip set mtime or add `filetime` crate.
-                 // Let's Skip for now and just Ack.
-                 
-                 Response::Ok
+            Request::Symlink { path, target } => {
+                 match create_symlink(Path::new(&path), &target, self.backup.as_ref()) {
+                     Ok(_) => Response::Ok,
+                     Err(e) => Response::Error { message: format!("Failed to create symlink: {}", e) },
+                 }
+            },
+            Request::HardLink { path, target } => {
+                 match hard_link(Path::new(&target), Path::new(&path), self.backup.as_ref()) {
+                     Ok(_) => Response::Ok,
+                     Err(e) => Response::Error { message: format!("Failed to create hard link: {}", e) },
+                 }
+            },
+            Request::SetMetadata { path, metadata } => {
+                 if let Some(incoming) = self.incoming.as_mut().filter(|i| i.path == Path::new(&path)) {
+                     incoming.metadata = Some(metadata);
+                     return Response::Ok;
+                 }
+                 match metadata.apply(Path::new(&path)) {
+                     Ok(_) => Response::Ok,
+                     Err(e) => Response::Error { message: format!("Failed to set metadata: {}", e) },
+                 }
             },
             Request::Delete { path } => {
-                 let p = Path::new(&path);
-                 if p.is_dir() {
-                     match std::fs::remove_dir_all(p) {
-                         Ok(_) => Response::Ok,
-                         Err(e) => Response::Error { message: e.to_string() },
-                     }
-                 } else {
-                     match std::fs::remove_file(p) {
-                         Ok(_) => Response::Ok,
-                         Err(e) => Response::Error { message: e.to_string() },
-                     }
+                 // Symlinks go themselves, never what they point to
+                 match remove_path(Path::new(&path), self.backup.as_ref()) {
+                     Ok(_) => Response::Ok,
+                     Err(e) => Response::Error { message: e.to_string() },
                  }
             }
+            Request::GetDelta { path, signature } => {
+                self.outgoing_ended = false;
+                if !valid_block_size(signature.block_size) {
+                    return invalid_block_size(signature.block_size);
+                }
+                match File::open(&path) {
+                    Ok(f) => {
+                        self.outgoing = Some(DeltaStream::new(BufReader::new(f), signature));
+                        self.next_delta_chunk()
+                    },
+                    Err(e) => Response::Error { message: e.to_string() },
+                }
+            },
+            Request::NextDeltaChunk => self.next_delta_chunk(),
+            Request::BeginDelta { path, block_size } => {
+                self.abort_incoming();
+                if !valid_block_size(block_size) {
+                    return invalid_block_size(block_size);
+                }
+
+                let path = PathBuf::from(path);
+                writable_parent(&path);
+                let tmp_path = temp_path(&path);
+                let tmp_file = match File::create(&tmp_path) {
+                    Ok(f) => HashWriter::new(BufWriter::new(f)),
+                    Err(e) => return Response::Error { message: format!("Failed to create temp file: {}", e) },
+                };
+
+                self.incoming = Some(IncomingDelta {
+                    old_file: File::open(self.basis_path(&path)).ok(),
+                    path,
+                    tmp_path,
+                    tmp_file,
+                    block_size,
+                    metadata: None,
+                });
+                Response::Ok
+            },
+            Request::DeltaChunk { ops } => {
+                let Some(incoming) = self.incoming.as_mut() else {
+                    return Response::Error { message: "No delta in progress".into() };
+                };
+
+                let mut empty_cursor = std::io::Cursor::new(vec![]);
+                let old_reader: &mut dyn ReadSeek = match &mut incoming.old_file {
+                    Some(f) => f,
+                    None => &mut empty_cursor,
+                };
+
+                match apply_ops(old_reader, &ops, &mut incoming.tmp_file, incoming.block_size) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => {
+                        self.abort_incoming();
+                        Response::Error { message: format!("Apply delta failed: {}", e) }
+                    }
+                }
+            },
+            Request::CommitDelta { final_size, checksum } => {
+                let Some(incoming) = self.incoming.take() else {
+                    return Response::Error { message: "No delta in progress".into() };
+                };
+                self.commit_delta(incoming, final_size, checksum)
+            }
+            Request::Batch { files } => {
+                let errors = files.into_iter()
+                    .map(|file| write_batch_file(file, self.backup.as_ref()).err().map(|e| e.to_string()))
+                    .collect();
+                Response::Batch { errors }
+            }
+            Request::SetBackup { backup } => {
+                self.backup = backup;
+                Response::Ok
+            }
+            Request::SetPartial { partial } => {
+                self.partial = partial;
+                Response::Ok
+            }
+        }
+    }
+
+    /// Move the current version of `path` aside if `SetBackup` asked for it.
+    fn save_backup(&self, path: &Path) -> Result<()> {
+        match &self.backup {
+            Some(backup) => backup.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// File a streamed delta for `path` is based on: its partial file from an
+    /// interrupted upload if there is one, otherwise the file itself.
+    fn basis_path(&self, path: &Path) -> PathBuf {
+        match &self.partial {
+            Some(partial) if partial.path_for(path).is_file() => partial.path_for(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn abort_incoming(&mut self) {
+        if let Some(incoming) = self.incoming.take() {
+            incoming.abort(self.partial.as_ref());
+        }
+    }
+
+    fn next_delta_chunk(&mut self) -> Response {
+        let Some(stream) = self.outgoing.as_mut() else {
+            // Pipelined requests past the last chunk end the stream quietly
+            if self.outgoing_ended {
+                return Response::Ok;
+            }
+            return Response::Error { message: "No delta in progress".into() };
+        };
+
+        match stream.next_chunk(DELTA_CHUNK_SIZE) {
+            Ok(ops) => {
+                let checksum = stream.is_finished().then(|| stream.checksum());
+                if checksum.is_some() {
+                    self.outgoing = None;
+                    self.outgoing_ended = true;
+                }
+                Response::DeltaChunk { ops, checksum }
+            },
+            Err(e) => {
+                self.outgoing = None;
+                Response::Error { message: format!("Compute delta failed: {}", e) }
+            }
+        }
+    }
+
+    fn commit_delta(&mut self, incoming: IncomingDelta, final_size: u64, checksum: Checksum) -> Response {
+        let IncomingDelta { path, tmp_path, old_file, tmp_file, metadata, .. } = incoming;
+        // Close the old file before renaming over it (required on Windows)
+        drop(old_file);
+
+        let written_checksum = tmp_file.checksum();
+        let result = (|| -> io::Result<bool> {
+            let mut tmp_file = tmp_file.into_inner().into_inner().map_err(|e| e.into_error())?;
+            let written = tmp_file.stream_position()?;
+            if written != final_size {
+                return Err(io::Error::other(format!("size mismatch: wrote {} bytes, expected {}", written, final_size)));
+            }
+            if written_checksum != checksum {
+                return Ok(false);
+            }
+            tmp_file.sync_all()?;
+            drop(tmp_file);
+            if let Some(metadata) = &metadata {
+                metadata.apply(&tmp_path).map_err(io::Error::other)?;
+            }
+            self.save_backup(&path).map_err(io::Error::other)?;
+            std::fs::rename(&tmp_path, &path)?;
+            Ok(true)
+        })();
+
+        // Used up. On a mismatch it is kept: the source may just have changed
+        // meanwhile, and the retry can still build on it.
+        if let (Some(partial), Ok(true)) = (&self.partial, &result) {
+            partial.remove(&path);
         }
+        match result {
+            Ok(true) => Response::Ok,
+            Ok(false) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                Response::ChecksumMismatch { path: path.to_string_lossy().to_string() }
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                Response::Error { message: format!("Commit delta failed: {}", e) }
+            }
+        }
+    }
+}
+
+/// Block sizes a client may use: 0 makes no sense, and the buffers are sized
+/// by it, so it is capped like --block-size.
+fn valid_block_size(block_size: usize) -> bool {
+    (1..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
+fn invalid_block_size(block_size: usize) -> Response {
+    Response::Error { message: format!("Invalid block size {} (must be 1..={})", block_size, MAX_BLOCK_SIZE) }
+}
+
+/// Write one whole file from a `Batch` via a temp file, checking its checksum first.
+fn write_batch_file(file: BatchFile, backup: Option<&Backup>) -> Result<()> {
+    let path = PathBuf::from(&file.path);
+    if crate::util::hash::hash_reader(&mut file.data.as_slice())? != file.checksum {
+        return Err(crate::FastSyncError::ChecksumMismatch { path });
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    writable_parent(&path);
+    let tmp_path = temp_path(&path);
+    let result = (|| -> io::Result<()> {
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&file.data)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        file.metadata.apply(&tmp_path).map_err(io::Error::other)?;
+        if let Some(backup) = backup {
+            backup.save(&path).map_err(io::Error::other)?;
+        }
+        std::fs::rename(&tmp_path, &path)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
     }
+    Ok(result?)
 }
@@ -1,14 +1,35 @@
 use crate::Result;
+use crate::apply::metadata::Metadata;
 use crate::scanner::FileEntry;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub mod ssh;
 
 pub trait Transport {
     fn exec(&self, command: &str) -> Result<String>;
     fn upload_file(&self, local: &Path, remote: &Path) -> Result<()>;
+    /// Download a remote file into a local path (pull mode).
+    fn download_file(&self, remote: &Path, local: &Path) -> Result<()>;
     /// List entries in a remote directory. Returns file metadata.
     fn list_dir(&self, path: &Path) -> Result<Vec<FileEntry>>;
     /// Recursively create a directory.
     fn create_dir_all(&self, path: &Path) -> Result<()>;
-}
\ No newline at end of file
+    /// Recursively create each of `paths`, returning each one's outcome.
+    fn create_dirs(&self, paths: &[PathBuf]) -> Vec<Result<()>> {
+        paths.iter().map(|path| self.create_dir_all(path)).collect()
+    }
+    /// Rename a remote file or directory, replacing an existing file at `to`.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+    /// Set the mtime, permission bits (unless 0) and owner and group (by id) of a
+    /// remote path. Xattrs are left alone.
+    fn set_metadata(&self, path: &Path, metadata: &Metadata) -> Result<()>;
+    /// Create a remote symlink at `path` pointing to `target`, replacing a file, link or
+    /// empty directory there.
+    fn symlink(&self, target: &str, path: &Path) -> Result<()>;
+}
+
+/// Remote paths with a drive letter (`D:/www`) are on Windows.
+pub fn is_windows_remote_path(remote_path: &str) -> bool {
+    let bytes = remote_path.as_bytes();
+    bytes.len() > 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic()
+}
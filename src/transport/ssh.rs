@@ -1,10 +1,17 @@
 use crate::Result;
-use crate::transport::Transport;
-use crate::scanner::FileEntry;
-use ssh2::{Session, Sftp};
+use crate::transport::{is_windows_remote_path, Transport};
+use crate::apply::local::temp_path;
+use crate::apply::metadata::Metadata;
+use crate::apply::partial::Partial;
+use crate::scanner::{FileEntry, FileKind};
+use crate::util::hash::{sha256_reader, to_hex};
+use crate::util::retry::RetryPolicy;
+use ssh2::{ErrorCode, FileStat, OpenFlags, OpenType, RenameFlags, Session, Sftp};
 use std::net::TcpStream;
 use std::path::{Path, PathBuf};
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+use tracing::{debug, info};
 
 #[derive(Clone)]
 pub struct SshConfig {
@@ -12,163 +19,426 @@ pub struct SshConfig {
     pub port: u16,
     pub user: String,
     pub key_path: Option<PathBuf>,
+    /// Ask for SSH transport compression
+    pub compress: bool,
+    /// Upload straight into the target file instead of a temp file renamed over it
+    pub inplace: bool,
+    /// Keep interrupted uploads and resume them
+    pub partial: Option<Partial>,
+    /// Retries for connecting, and for operations that fail on a broken connection
+    pub retry: RetryPolicy,
 }
 
+/// An SSH connection that is re-established when it breaks.
 pub struct SshConnection {
+    config: SshConfig,
+    link: Mutex<Link>,
+}
+
+/// One established SSH session.
+struct Link {
     session: Session,
     _tcp: TcpStream,
+    /// Bumped on every reconnect, so threads that hit the same failure reconnect once
+    generation: u64,
 }
 
 impl Transport for SshConnection {
     fn exec(&self, command: &str) -> Result<String> {
-        let mut channel = self.session.channel_session()
-            .map_err(|e| crate::FastSyncError::SshConnection(format!("Channel open failed: {}", e)))?;
-        channel.exec(command)
-            .map_err(|e| crate::FastSyncError::RemoteCommand(format!("Exec failed: {}", e)))?;
-        
-        let mut s = String::new();
-        channel.read_to_string(&mut s)
-            .map_err(crate::FastSyncError::Io)?;
-            
-        channel.wait_close().ok(); 
-        let exit_status = channel.exit_status().unwrap_or(0);
-        
-        if exit_status != 0 {
-             return Err(crate::FastSyncError::RemoteCommand(format!("Command '{}' exited with code {}. Output: {}", command, exit_status, s)));
-        }
-        
-        Ok(s)
+        self.retrying("Remote command", |session| exec_on(session, command))
     }
 
     fn upload_file(&self, local: &Path, remote: &Path) -> Result<()> {
-        let mut local_file = std::fs::File::open(local).map_err(crate::FastSyncError::Io)?;
-        let sftp = self.sftp()?;
-        
-        let mut remote_file = sftp.create(remote)
-            .map_err(|e| crate::FastSyncError::SshConnection(format!("Remote file create failed {:?}: {}", remote, e)))?;
-            
-        std::io::copy(&mut local_file, &mut remote_file).map_err(crate::FastSyncError::Io)?;
-        
-        Ok(())
+        self.upload_file_with(local, remote, |_| Ok(()))
+    }
+
+    fn download_file(&self, remote: &Path, local: &Path) -> Result<()> {
+        self.retrying("Download", |session| {
+            let sftp = open_sftp(session)?;
+            let mut remote_file = sftp.open(remote)
+                .map_err(|e| sftp_error(format!("Remote file open failed {:?}", remote), e))?;
+
+            let mut local_file = std::fs::File::create(local).map_err(crate::FastSyncError::Io)?;
+            std::io::copy(&mut remote_file, &mut local_file).map_err(crate::FastSyncError::from_channel)?;
+
+            Ok(())
+        })
     }
 
     fn list_dir(&self, path: &Path) -> Result<Vec<FileEntry>> {
-        let sftp = self.sftp()?;
-        let items = sftp.readdir(path)
-            .map_err(|e| crate::FastSyncError::SshConnection(format!("SFTP readdir failed for {:?}: {}", path, e)))?;
-
-        let mut entries = Vec::new();
-        for (pb, stat) in items {
-            let file_name = pb.file_name().and_then(|n| n.to_str()).unwrap_or("");
-            if file_name == "." || file_name == ".." || file_name.is_empty() {
-                continue;
+        self.retrying("Remote listing", |session| {
+            let sftp = open_sftp(session)?;
+            let items = sftp.readdir(path)
+                .map_err(|e| sftp_error(format!("SFTP readdir failed for {:?}", path), e))?;
+
+            let mut entries = Vec::new();
+            for (pb, stat) in items {
+                let file_name = pb.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if file_name == "." || file_name == ".." || file_name.is_empty() {
+                    continue;
+                }
+
+                let file_type = stat.file_type();
+                let (kind, link_target) = if file_type.is_symlink() {
+                    let target = sftp.readlink(&pb)
+                        .map_err(|e| sftp_error(format!("SFTP readlink failed for {:?}", pb), e))?;
+                    (FileKind::Symlink, Some(target.to_string_lossy().to_string()))
+                } else if file_type.is_dir() {
+                    (FileKind::Dir, None)
+                } else {
+                    (FileKind::File, None)
+                };
+
+                // readdir returns the full path; entries carry just the name and the
+                // recursive scanner prepends the parent path.
+                entries.push(FileEntry {
+                    path: file_name.to_string(),
+                    size: stat.size.unwrap_or(0),
+                    mtime: stat.mtime.unwrap_or(0) as i64,
+                    mode: stat.perm.unwrap_or(0),
+                    kind,
+                    link_target,
+                    uid: stat.uid.unwrap_or(0),
+                    gid: stat.gid.unwrap_or(0),
+                    ..Default::default()
+                });
             }
+            Ok(entries)
+        })
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        // `std::path::Path` follows the local OS, so remote roots like `D:/` can't be
+        // found by walking components; create_dir_recursive stats its way up instead.
+        self.retrying("Remote mkdir", |session| create_dir_recursive(&open_sftp(session)?, path))
+    }
 
-            // PathBuf from ssh2 might be absolute or relative depending on readdir implementation, 
-            // usually it is just the filename or path relative to the searched dir?
-            // Actually ssh2 readdir usually returns the full path provided + filename.
-            // But we want just the filename here to let the caller handle full paths?
-            // Wait, FileEntry usually needs relative path from root. 
-            // Transport::list_dir returns entries for *that* dir. 
-            // Let's store just the filename in path for now, or let the caller reconstruct?
-            // The `FileEntry` struct expects `path` to be "Relative path (using / as separator)".
-            // BUT, `list_dir` is generic. It should probably return just the name, or the path relative to the `path` argument.
-            // `readdir` returns the path.
-            // Let's rely on `file_name` for the entry path, and let the recursive scanner prepend the parent path.
-
-            entries.push(FileEntry {
-                path: file_name.to_string(), 
-                size: stat.size.unwrap_or(0),
-                mtime: stat.mtime.unwrap_or(0) as i64,
-                mode: stat.perm.unwrap_or(0),
-                is_dir: stat.is_dir(),
-            });
+    fn create_dirs(&self, paths: &[PathBuf]) -> Vec<Result<()>> {
+        // One SFTP session for all of them, rather than one each
+        match self.sftp() {
+            Ok(sftp) => paths.iter().map(|path| match create_dir_recursive(&sftp, path) {
+                // The session broke halfway; start over on a fresh one
+                Err(e) if e.is_transient() => self.create_dir_all(path),
+                result => result,
+            }).collect(),
+            Err(e) => {
+                let message = e.to_string();
+                paths.iter().map(|_| Err(crate::FastSyncError::SshConnection(message.clone()))).collect()
+            }
         }
-        Ok(entries)
     }
 
-    fn create_dir_all(&self, path: &Path) -> Result<()> {
-        let sftp = self.sftp()?;
-        // Naive implementation: Try to create, if fail, try parent. 
-        // Better: Iterate components.
-        // Note: paths might be absolute.
-        
-        let mut stack = Vec::new();
-        let mut p = path;
-        while let Some(parent) = p.parent() {
-            stack.push(p);
-            p = parent;
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.retrying("Remote rename", |session| rename_over(session, &open_sftp(session)?, from, to))
+    }
+
+    fn set_metadata(&self, path: &Path, metadata: &Metadata) -> Result<()> {
+        self.retrying("Remote setstat", |session| {
+            let sftp = open_sftp(session)?;
+            let failed = |e| sftp_error(format!("SFTP setstat failed for {:?}", path), e);
+            let unset = FileStat { size: None, uid: None, gid: None, perm: None, atime: None, mtime: None };
+            // First, as chown may clear setuid/setgid bits. SFTP sets both ids at once,
+            // so one not asked for is kept as it is.
+            if metadata.owner.is_some() || metadata.group.is_some() {
+                let current = sftp.stat(path).map_err(failed)?;
+                sftp.setstat(path, FileStat {
+                    uid: metadata.owner.as_ref().map(|owner| owner.id).or(current.uid),
+                    gid: metadata.group.as_ref().map(|group| group.id).or(current.gid),
+                    ..unset.clone()
+                }).map_err(failed)?;
+            }
+            // Access and modification time go together too; like `touch -d`, both get the mtime
+            let mtime = u64::try_from(metadata.mtime).unwrap_or(0);
+            sftp.setstat(path, FileStat {
+                perm: (metadata.mode != 0).then_some(metadata.mode & 0o7777),
+                atime: Some(mtime),
+                mtime: Some(mtime),
+                ..unset
+            }).map_err(failed)
+        })
+    }
+
+    fn symlink(&self, target: &str, path: &Path) -> Result<()> {
+        self.retrying("Remote symlink", |session| {
+            let sftp = open_sftp(session)?;
+            // SFTP can't create over an existing entry. Like rsync without --force,
+            // only an empty directory makes way.
+            match sftp.lstat(path) {
+                Ok(stat) if stat.is_dir() => sftp.rmdir(path).map_err(|e| sftp_error(
+                    format!("Can't replace directory {:?} with a symlink (not empty?)", path), e))?,
+                Ok(_) => sftp.unlink(path)
+                    .map_err(|e| sftp_error(format!("Can't replace {:?} with a symlink", path), e))?,
+                Err(_) => {}
+            }
+            // ssh2 names these the other way round: the link goes at its second argument
+            sftp.symlink(Path::new(target), path)
+                .map_err(|e| sftp_error(format!("SFTP symlink {:?} -> {} failed", path, target), e))
+        })
+    }
+}
+
+fn exec_on(session: &Session, command: &str) -> Result<String> {
+    let mut channel = session.channel_session()
+        .map_err(|e| crate::FastSyncError::SshConnection(format!("Channel open failed: {}", e)))?;
+    channel.exec(command)
+        .map_err(|e| crate::FastSyncError::RemoteCommand(format!("Exec failed: {}", e)))?;
+
+    let mut s = String::new();
+    channel.read_to_string(&mut s)
+        .map_err(crate::FastSyncError::from_channel)?;
+
+    channel.wait_close().ok();
+    let exit_status = channel.exit_status().unwrap_or(0);
+
+    if exit_status != 0 {
+         return Err(crate::FastSyncError::RemoteCommand(format!("Command '{}' exited with code {}. Output: {}", command, exit_status, s)));
+    }
+
+    Ok(s)
+}
+
+/// Rename `from` to `to`, atomically replacing an existing file at `to`.
+fn rename_over(session: &Session, sftp: &Sftp, from: &Path, to: &Path) -> Result<()> {
+    let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+    let error = match sftp.rename(from, to, flags) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    // SFTP v3 servers (OpenSSH) ignore the flags and refuse to rename over an
+    // existing file. Removing it first would leave nothing there if the rename
+    // then failed, so a shell rename replaces it instead. Directories never are,
+    // nor links to them, which `mv` would move the file into.
+    let replaceable = sftp.lstat(to).is_ok_and(|stat| !stat.is_dir()) && !sftp.stat(to).is_ok_and(|stat| stat.is_dir());
+    if !replaceable {
+        return Err(sftp_error(format!("Rename {:?} -> {:?} failed", from, to), error));
+    }
+    let (from, to) = (from.to_string_lossy(), to.to_string_lossy());
+    let cmd = if is_windows_remote_path(&to) {
+        format!(
+            "powershell -NoProfile -NonInteractive -Command \"[System.IO.File]::Replace('{}', '{}', [NullString]::Value)\"",
+            from.replace('\'', "''"),
+            to.replace('\'', "''")
+        )
+    } else {
+        format!("mv -f -- '{}' '{}'", from.replace('\'', "'\\''"), to.replace('\'', "'\\''"))
+    };
+    exec_on(session, &cmd)?;
+    Ok(())
+}
+
+fn create_dir_recursive(sftp: &Sftp, path: &Path) -> Result<()> {
+    // Check if exists
+    if sftp.stat(path).is_ok() {
+        return Ok(());
+    }
+
+    // Try to create parent first
+    if let Some(parent) = path.parent() {
+        // Avoid infinite recursion if parent is same as path (root)
+        if parent != path && !parent.as_os_str().is_empty() {
+            create_dir_recursive(sftp, parent)?;
+        }
+    }
+
+    // Create current
+    // Mode 0o755 is standard for dirs
+    match sftp.mkdir(path, 0o755) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            // Check again if it exists (race condition or root drive, e.g. mkdir("D:")
+            // fails but stat("D:") passes)
+            if sftp.stat(path).is_ok() {
+                Ok(())
+            } else {
+                Err(sftp_error(format!("Failed to create dir {:?}", path), e))
+            }
         }
-        stack.push(p); // Push root or first component? 
-        // Path::components might be better but handling absolute paths on remote (Windows D:/) is tricky via Path.
-        
-        // Let's rely on the fact that we usually create directories that are subdirs of our target.
-        // But here we need generic support.
-        
-        // Alternative: Use SFTP to stat. If missing, create.
-        // Iterate from root is hard if we don't know the root format (C:\ vs /).
-        // Let's try to create from the top down? No, we need to know where to start.
-        
-        // Let's just try to create. If it fails, assume it exists? No.
-        // Actually, we can just split by components.
-        
-        // Problem: `std::path::Path` logic depends on local OS. 
-        // If Linux client connects to Windows, Path might not parse `D:\` correctly as root.
-        // But `D:/dist` is parsed as `D:` (prefix?)
-        
-        // Workaround for MVP:
-        // Attempt to create the full path. If it fails, try parent. 
-        // Recursion?
-        
-        self.create_dir_recursive(&sftp, path)
+    }
+}
+
+fn open_sftp(session: &Session) -> Result<Sftp> {
+    session.sftp().map_err(|e| crate::FastSyncError::SshConnection(format!("SFTP init failed: {}", e)))
+}
+
+/// Status codes from the SFTP server concern one file; anything else means the
+/// connection itself failed.
+fn sftp_error(context: String, e: ssh2::Error) -> crate::FastSyncError {
+    match e.code() {
+        ErrorCode::SFTP(_) => crate::FastSyncError::RemoteCommand(format!("{}: {}", context, e)),
+        ErrorCode::Session(_) => crate::FastSyncError::SshConnection(format!("{}: {}", context, e)),
     }
 }
 
 impl SshConnection {
-    fn create_dir_recursive(&self, sftp: &Sftp, path: &Path) -> Result<()> {
-        // Check if exists
-        if sftp.stat(path).is_ok() {
+    pub fn connect(config: &SshConfig) -> Result<Self> {
+        let link = config.retry.run("SSH connect", || Link::open(config, 0))?;
+        Ok(Self { config: config.clone(), link: Mutex::new(link) })
+    }
+
+    pub fn sftp(&self) -> Result<Sftp> {
+        self.retrying("SFTP init", open_sftp)
+    }
+
+    pub fn open_channel(&self) -> Result<ssh2::Channel> {
+        self.retrying("Channel open", |session| {
+            session.channel_session()
+                 .map_err(|e| crate::FastSyncError::SshConnection(format!("Channel open failed: {}", e)))
+        })
+    }
+
+    /// Run `op` on the current session. Transient failures are retried per the
+    /// retry policy, reconnecting first.
+    fn retrying<T, F>(&self, what: &str, mut op: F) -> Result<T>
+    where
+        F: FnMut(&Session) -> Result<T>,
+    {
+        let mut failed = None;
+        self.config.retry.run(what, || {
+            if let Some(generation) = failed.take() {
+                self.reconnect(generation)?;
+            }
+            let (session, generation) = {
+                let link = self.link.lock().unwrap();
+                (link.session.clone(), link.generation)
+            };
+            op(&session).inspect_err(|e| {
+                if e.is_transient() {
+                    failed = Some(generation);
+                }
+            })
+        })
+    }
+
+    /// Replace the session that failed, unless another thread already did.
+    fn reconnect(&self, failed_generation: u64) -> Result<()> {
+        let mut link = self.link.lock().unwrap();
+        if link.generation == failed_generation {
+            info!("Reconnecting to {}:{}...", self.config.host, self.config.port);
+            *link = Link::open(&self.config, failed_generation + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Upload like `upload_file`, calling `before_rename` with the complete temp file
+    /// just before it replaces `remote`: the place to check it and move the old file
+    /// aside. An error leaves `remote` alone. Not called with `inplace`.
+    pub fn upload_file_with<F>(&self, local: &Path, remote: &Path, mut before_rename: F) -> Result<()>
+    where
+        F: FnMut(&Path) -> Result<()>,
+    {
+        // Safe to repeat: the target is only replaced once the upload is complete
+        self.retrying("Upload", |session| self.upload_on(session, local, remote, &mut before_rename))
+    }
+
+    fn upload_on(&self, session: &Session, local: &Path, remote: &Path, before_rename: &mut dyn FnMut(&Path) -> Result<()>) -> Result<()> {
+        let mut local_file = std::fs::File::open(local).map_err(crate::FastSyncError::Io)?;
+        let sftp = open_sftp(session)?;
+
+        if self.config.inplace {
+            let mut remote_file = sftp.create(remote)
+                .map_err(|e| sftp_error(format!("Remote file create failed {:?}", remote), e))?;
+            std::io::copy(&mut local_file, &mut remote_file).map_err(crate::FastSyncError::from_channel)?;
             return Ok(());
         }
-        
-        // Try to create parent first
-        if let Some(parent) = path.parent() {
-            // Avoid infinite recursion if parent is same as path (root)
-            if parent != path && parent.as_os_str().len() > 0 {
-                self.create_dir_recursive(sftp, parent)?;
+
+        // Write next to the target and rename over it, so readers never see a partial file
+        let partial = self.config.partial.as_ref();
+        let tmp = match partial {
+            Some(partial) => partial.path_for(remote),
+            None => temp_path(remote),
+        };
+        let result = (|| -> Result<()> {
+            let mut remote_file = match self.resume_offset(session, &sftp, &tmp, &mut local_file)? {
+                Some(offset) => {
+                    debug!("Resuming {:?} at {} bytes", remote, offset);
+                    let mut remote_file = sftp.open_mode(&tmp, OpenFlags::WRITE, 0o644, OpenType::File)
+                        .map_err(|e| sftp_error(format!("Remote file open failed {:?}", tmp), e))?;
+                    remote_file.seek(SeekFrom::Start(offset)).map_err(crate::FastSyncError::from_channel)?;
+                    local_file.seek(SeekFrom::Start(offset)).map_err(crate::FastSyncError::Io)?;
+                    remote_file
+                }
+                None => sftp.create(&tmp)
+                    .map_err(|e| sftp_error(format!("Remote file create failed {:?}", tmp), e))?,
+            };
+            std::io::copy(&mut local_file, &mut remote_file).map_err(crate::FastSyncError::from_channel)?;
+            // Needs the fsync@openssh.com extension; other servers flush on close
+            if let Err(e) = remote_file.fsync() {
+                debug!("SFTP fsync unavailable for {:?}: {}", tmp, e);
             }
-        }
-        
-        // Create current
-        // Mode 0o755 is standard for dirs
-        match sftp.mkdir(path, 0o755) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                // Check again if it exists (race condition or root drive)
-                if sftp.stat(path).is_ok() {
-                    Ok(())
-                } else {
-                    // Ignore error for drive letters or roots?
-                    // e.g. mkdir("D:") might fail.
-                    // We assume that if stat failed and mkdir failed, it's an error.
-                    // BUT, `stat("D:")` should pass.
-                    Err(crate::FastSyncError::SshConnection(format!("Failed to create dir {:?}: {}", path, e)))
+            drop(remote_file);
+            before_rename(&tmp)?;
+            rename_over(session, &sftp, &tmp, remote)
+        })();
+
+        match (partial, &result) {
+            // Checked against the source afterwards, so a stale partial only costs a retry
+            (Some(partial), Ok(())) if partial.dir.is_some() => {
+                if let Some(dir) = tmp.parent() {
+                    sftp.rmdir(dir).ok();
                 }
             }
+            (Some(_), _) => {}
+            (None, Err(_)) => {
+                sftp.unlink(&tmp).ok();
+            }
+            (None, Ok(())) => {}
         }
+        result
     }
 
-    pub fn connect(config: &SshConfig) -> Result<Self> {
+    /// Where to resume an upload into the partial file `tmp`, if it holds a usable prefix.
+    fn resume_offset(&self, session: &Session, sftp: &Sftp, tmp: &Path, local_file: &mut std::fs::File) -> Result<Option<u64>> {
+        if self.config.partial.is_none() {
+            return Ok(None);
+        }
+        if let Some(parent) = tmp.parent() {
+            create_dir_recursive(sftp, parent)?;
+        }
+        let local_size = local_file.metadata().map_err(crate::FastSyncError::Io)?.len();
+        let size = match sftp.stat(tmp).ok().and_then(|stat| stat.size) {
+            Some(size) if size > 0 && size < local_size => size,
+            _ => return Ok(None),
+        };
+        let matches = partial_matches(session, tmp, local_file, size)?;
+        local_file.rewind().map_err(crate::FastSyncError::Io)?;
+        if !matches {
+            debug!("Partial file {:?} doesn't match the source; starting over", tmp);
+        }
+        Ok(matches.then_some(size))
+    }
+}
+
+/// Whether the first `size` bytes of `local` are what the remote `partial` holds.
+/// The remote side is hashed with `sha256sum`; without it (or on Windows) the
+/// partial can't be checked and counts as different.
+fn partial_matches(session: &Session, partial: &Path, local: &mut std::fs::File, size: u64) -> Result<bool> {
+    let path = partial.to_string_lossy().replace('\'', "'\\''");
+    let cmd = format!("head -c {} -- '{}' | sha256sum", size, path);
+    let out = match exec_on(session, &cmd) {
+        Ok(out) => out,
+        Err(e) if e.is_transient() => return Err(e),
+        Err(_) => return Ok(false),
+    };
+    let Some(remote_hex) = out.split_whitespace().next() else {
+        return Ok(false);
+    };
+    let local_hash = sha256_reader(&mut local.take(size)).map_err(crate::FastSyncError::Io)?;
+    Ok(to_hex(&local_hash) == remote_hex.to_ascii_lowercase())
+}
+
+impl Link {
+    fn open(config: &SshConfig, generation: u64) -> Result<Self> {
         let tcp = TcpStream::connect((config.host.as_str(), config.port))
             .map_err(|e| crate::FastSyncError::SshConnection(format!("Failed to connect to {}:{}: {}", config.host, config.port, e)))?;
-        
+
         let mut session = Session::new()
              .map_err(|e| crate::FastSyncError::SshConnection(e.to_string()))?;
-        
+
         session.set_tcp_stream(tcp.try_clone().map_err(crate::FastSyncError::Io)?);
+        session.set_compress(config.compress);
         session.handshake()
              .map_err(|e| crate::FastSyncError::SshConnection(format!("Handshake failed: {}", e)))?;
-             
+
         if let Some(key) = &config.key_path {
              session.userauth_pubkey_file(&config.user, None, key, None)
                  .map_err(|e| crate::FastSyncError::Authentication(format!("Key auth failed: {}", e)))?;
@@ -181,34 +451,22 @@ impl SshConnection {
                      PathBuf::from(&home).join(".ssh/id_rsa"),
                      PathBuf::from(&home).join(".ssh/id_ed25519"),
                  ];
-                 
+
                  for key in default_keys {
                      if key.exists() {
                          // Try auth with this key
-                         if session.userauth_pubkey_file(&config.user, None, &key, None).is_ok() {
-                             if session.authenticated() {
-                                 break;
-                             }
+                         if session.userauth_pubkey_file(&config.user, None, &key, None).is_ok() && session.authenticated() {
+                             break;
                          }
                      }
                  }
              }
         }
-        
+
         if !session.authenticated() {
             return Err(crate::FastSyncError::Authentication("Authentication failed (Agent and default keys tried)".into()));
         }
 
-        Ok(Self { session, _tcp: tcp })
-    }
-    
-    pub fn sftp(&self) -> Result<Sftp> {
-        self.session.sftp().map_err(|e| crate::FastSyncError::SshConnection(format!("SFTP init failed: {}", e)))
-    }
-
-    pub fn open_channel(&self) -> Result<ssh2::Channel> {
-        self.session.channel_session()
-             .map_err(|e| crate::FastSyncError::SshConnection(format!("Channel open failed: {}", e)))
+        Ok(Self { session, _tcp: tcp, generation })
     }
 }
-
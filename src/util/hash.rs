@@ -0,0 +1,87 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Whole-file BLAKE3 digest
+pub type Checksum = [u8; 32];
+
+/// BLAKE3 of everything `reader` yields.
+pub fn hash_reader<R: Read>(reader: &mut R) -> io::Result<Checksum> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// BLAKE3 of a file's content.
+pub fn hash_file(path: &Path) -> io::Result<Checksum> {
+    let mut file = io::BufReader::new(std::fs::File::open(path)?);
+    hash_reader(&mut file)
+}
+
+/// SHA-256 of a file's content, for remotes that only have `sha256sum`.
+pub fn sha256_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = io::BufReader::new(std::fs::File::open(path)?);
+    sha256_reader(&mut file)
+}
+
+/// SHA-256 of everything `reader` yields.
+pub fn sha256_reader<R: Read>(reader: &mut R) -> io::Result<[u8; 32]> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writer that hashes everything written through it.
+pub struct HashWriter<W: Write> {
+    inner: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, hasher: blake3::Hasher::new() }
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        *self.hasher.finalize().as_bytes()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_writer_matches_hash_reader() {
+        let data = b"fastsync checksum".repeat(1000);
+        let mut writer = HashWriter::new(Vec::new());
+        writer.write_all(&data).unwrap();
+
+        assert_eq!(writer.checksum(), hash_reader(&mut data.as_slice()).unwrap());
+        assert_eq!(to_hex(&[0x0f, 0xa0]), "0fa0");
+    }
+}
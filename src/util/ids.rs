@@ -0,0 +1,141 @@
+//! User and group name lookups, so --owner/--group can map ids by name
+//! between machines whose numeric ids differ (unless --numeric-ids).
+
+#[cfg(unix)]
+mod imp {
+    use std::collections::HashMap;
+    use std::ffi::{CStr, CString};
+    use std::sync::{Mutex, OnceLock};
+
+    type Cache<K> = OnceLock<Mutex<HashMap<K, Option<(u32, String)>>>>;
+
+    static USERS_BY_ID: Cache<u32> = OnceLock::new();
+    static USERS_BY_NAME: Cache<String> = OnceLock::new();
+    static GROUPS_BY_ID: Cache<u32> = OnceLock::new();
+    static GROUPS_BY_NAME: Cache<String> = OnceLock::new();
+
+    fn cached<K, F>(cache: &Cache<K>, key: K, lookup: F) -> Option<(u32, String)>
+    where
+        K: std::hash::Hash + Eq + Clone,
+        F: FnOnce(&K) -> Option<(u32, String)>,
+    {
+        let cache = cache.get_or_init(Default::default);
+        if let Some(hit) = cache.lock().unwrap().get(&key) {
+            return hit.clone();
+        }
+        let found = lookup(&key);
+        cache.lock().unwrap().insert(key, found.clone());
+        found
+    }
+
+    /// Run a reentrant `get*_r` call, growing the buffer while it reports ERANGE.
+    fn with_buffer<F>(mut call: F) -> Option<(u32, String)>
+    where
+        F: FnMut(&mut [libc::c_char]) -> Result<Option<(u32, String)>, libc::c_int>,
+    {
+        let mut buf = vec![0 as libc::c_char; 1024];
+        loop {
+            match call(&mut buf) {
+                Ok(found) => return found,
+                Err(libc::ERANGE) if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    unsafe fn string(ptr: *const libc::c_char) -> String {
+        CStr::from_ptr(ptr).to_string_lossy().into_owned()
+    }
+
+    fn passwd(by: impl Fn(*mut libc::passwd, &mut [libc::c_char], *mut *mut libc::passwd) -> libc::c_int) -> Option<(u32, String)> {
+        with_buffer(|buf| {
+            let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+            let mut result = std::ptr::null_mut();
+            match by(&mut pwd, buf, &mut result) {
+                0 if result.is_null() => Ok(None),
+                0 => Ok(Some((pwd.pw_uid, unsafe { string(pwd.pw_name) }))),
+                err => Err(err),
+            }
+        })
+    }
+
+    fn group(by: impl Fn(*mut libc::group, &mut [libc::c_char], *mut *mut libc::group) -> libc::c_int) -> Option<(u32, String)> {
+        with_buffer(|buf| {
+            let mut grp: libc::group = unsafe { std::mem::zeroed() };
+            let mut result = std::ptr::null_mut();
+            match by(&mut grp, buf, &mut result) {
+                0 if result.is_null() => Ok(None),
+                0 => Ok(Some((grp.gr_gid, unsafe { string(grp.gr_name) }))),
+                err => Err(err),
+            }
+        })
+    }
+
+    pub fn user_name(uid: u32) -> Option<String> {
+        cached(&USERS_BY_ID, uid, |&uid| passwd(|pwd, buf, result| unsafe {
+            libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result)
+        }))
+        .map(|(_, name)| name)
+    }
+
+    pub fn user_id(name: &str) -> Option<u32> {
+        cached(&USERS_BY_NAME, name.to_string(), |name| {
+            let name = CString::new(name.as_str()).ok()?;
+            passwd(|pwd, buf, result| unsafe {
+                libc::getpwnam_r(name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result)
+            })
+        })
+        .map(|(id, _)| id)
+    }
+
+    pub fn group_name(gid: u32) -> Option<String> {
+        cached(&GROUPS_BY_ID, gid, |&gid| group(|grp, buf, result| unsafe {
+            libc::getgrgid_r(gid, grp, buf.as_mut_ptr(), buf.len(), result)
+        }))
+        .map(|(_, name)| name)
+    }
+
+    pub fn group_id(name: &str) -> Option<u32> {
+        cached(&GROUPS_BY_NAME, name.to_string(), |name| {
+            let name = CString::new(name.as_str()).ok()?;
+            group(|grp, buf, result| unsafe {
+                libc::getgrnam_r(name.as_ptr(), grp, buf.as_mut_ptr(), buf.len(), result)
+            })
+        })
+        .map(|(id, _)| id)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub fn user_name(_uid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn user_id(_name: &str) -> Option<u32> {
+        None
+    }
+
+    pub fn group_name(_gid: u32) -> Option<String> {
+        None
+    }
+
+    pub fn group_id(_name: &str) -> Option<u32> {
+        None
+    }
+}
+
+pub use imp::{group_id, group_name, user_id, user_name};
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_round_trips() {
+        assert_eq!(user_name(0).as_deref(), Some("root"));
+        assert_eq!(user_id("root"), Some(0));
+        assert_eq!(group_id(&group_name(0).unwrap()), Some(0));
+        assert_eq!(user_id("no-such-user-fastsync"), None);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod hash;
+pub mod ids;
+pub mod retry;
+pub mod xattr;
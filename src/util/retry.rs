@@ -0,0 +1,79 @@
+use crate::Result;
+use std::time::Duration;
+use tracing::warn;
+
+/// How often and how patiently transient failures are retried (--retries, --retry-delay).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Wait before the first retry; doubled for every further one
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { retries: 3, delay: Duration::from_secs(1) }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `attempt` (counting from 0).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.delay.saturating_mul(1 << attempt.min(16))
+    }
+
+    /// Run `op`, retrying it while it fails with a transient error.
+    pub fn run<T, F>(&self, what: &str, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if e.is_transient() && attempt < self.retries => {
+                    let wait = self.backoff(attempt);
+                    attempt += 1;
+                    warn!("{} failed: {}; retrying in {:?} ({}/{})", what, e, wait, attempt, self.retries);
+                    std::thread::sleep(wait);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FastSyncError;
+
+    #[test]
+    fn test_retries_only_transient_errors() {
+        let policy = RetryPolicy { retries: 2, delay: Duration::ZERO };
+        assert_eq!(RetryPolicy::default().backoff(2), Duration::from_secs(4));
+
+        let mut calls = 0;
+        let result = policy.run("connect", || {
+            calls += 1;
+            if calls < 3 { Err(FastSyncError::SshConnection("reset".into())) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        calls = 0;
+        let result: Result<()> = policy.run("connect", || {
+            calls += 1;
+            Err(FastSyncError::SshConnection("down".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        calls = 0;
+        let result: Result<()> = policy.run("upload", || {
+            calls += 1;
+            Err(FastSyncError::Authentication("denied".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,196 @@
+//! Extended attributes (--xattrs) and POSIX ACLs (--acls), which Linux keeps
+//! as the `system.posix_acl_*` attributes. Links are never followed.
+
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Attribute values by name
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Attributes holding the access and default ACLs
+const ACL_NAMES: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Which attributes a sync carries: ACLs with --acls, all others with --xattrs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XattrScope {
+    pub xattrs: bool,
+    pub acls: bool,
+}
+
+impl XattrScope {
+    pub fn is_empty(&self) -> bool {
+        !self.xattrs && !self.acls
+    }
+
+    pub fn includes(&self, name: &str) -> bool {
+        if ACL_NAMES.contains(&name) {
+            self.acls
+        } else {
+            self.xattrs
+        }
+    }
+}
+
+/// The attributes of `path` within `scope`.
+pub fn read(path: &Path, scope: XattrScope) -> Result<Xattrs> {
+    let mut values = Xattrs::new();
+    for name in imp::list(path)? {
+        if scope.includes(&name) {
+            // None when removed since the listing
+            if let Some(value) = imp::get(path, &name)? {
+                values.insert(name, value);
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Make the attributes of `path` within `scope` exactly `values`.
+pub fn write(path: &Path, scope: XattrScope, values: &Xattrs) -> Result<()> {
+    let current = read(path, scope)?;
+    for name in current.keys().filter(|name| !values.contains_key(*name)) {
+        imp::remove(path, name)?;
+    }
+    for (name, value) in values {
+        if scope.includes(name) && current.get(name) != Some(value) {
+            imp::set(path, name, value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use crate::Result;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    fn c_path(path: &Path) -> Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e).into())
+    }
+
+    fn c_name(name: &str) -> Result<CString> {
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e).into())
+    }
+
+    /// Call `fill` with a buffer sized by a first, empty call, retrying when the
+    /// value grew in between. `None` when the attribute is gone.
+    fn sized<F>(fill: F) -> io::Result<Option<Vec<u8>>>
+    where
+        F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+    {
+        loop {
+            let len = fill(std::ptr::null_mut(), 0);
+            if len < 0 {
+                return missing(io::Error::last_os_error());
+            }
+            let mut buf = vec![0u8; len as usize];
+            let len = fill(buf.as_mut_ptr().cast(), buf.len());
+            if len >= 0 {
+                buf.truncate(len as usize);
+                return Ok(Some(buf));
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ERANGE) {
+                return missing(err);
+            }
+        }
+    }
+
+    fn missing(err: io::Error) -> io::Result<Option<Vec<u8>>> {
+        match err.raw_os_error() {
+            Some(libc::ENODATA) => Ok(None),
+            _ => Err(err),
+        }
+    }
+
+    pub fn list(path: &Path) -> Result<Vec<String>> {
+        let path = c_path(path)?;
+        let names = match sized(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), len) }) {
+            Ok(names) => names.unwrap_or_default(),
+            // The filesystem has no attributes at all
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(names
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect())
+    }
+
+    pub fn get(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+        let (path, name) = (c_path(path)?, c_name(name)?);
+        Ok(sized(|buf, len| unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, len) })?)
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+        let (path, name) = (c_path(path)?, c_name(name)?);
+        let ret = unsafe { libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    pub fn remove(path: &Path, name: &str) -> Result<()> {
+        let (path, name) = (c_path(path)?, c_name(name)?);
+        if unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ENODATA) {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use crate::Result;
+    use std::path::Path;
+
+    pub fn list(_path: &Path) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    pub fn get(_path: &Path, _name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    pub fn set(_path: &Path, name: &str, _value: &[u8]) -> Result<()> {
+        Err(crate::FastSyncError::Config(format!("cannot set {}: extended attributes are only supported on Linux", name)))
+    }
+
+    pub fn remove(_path: &Path, _name: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "data").unwrap();
+        if imp::set(&path, "user.probe", b"1").is_err() {
+            // No user xattrs on this filesystem
+            return;
+        }
+
+        let scope = XattrScope { xattrs: true, acls: false };
+        let values = Xattrs::from([("user.origin".to_string(), b"build-42".to_vec())]);
+        write(&path, scope, &values).unwrap();
+        assert_eq!(read(&path, scope).unwrap(), values);
+        // ACLs alone don't see user attributes
+        assert!(read(&path, XattrScope { xattrs: false, acls: true }).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,449 @@
+use assert_cmd::Command;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn fastsync() -> Command {
+    assert_cmd::cargo::cargo_bin_cmd!("fastsync")
+}
+
+fn set_mtime(path: &Path, secs: u64) {
+    let file = fs::File::options().write(true).open(path).unwrap();
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+}
+
+#[test]
+fn test_local_sync_copies_and_deletes() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    fs::write(src.path().join("a.txt"), "hello").unwrap();
+    fs::create_dir(src.path().join("sub")).unwrap();
+    fs::write(src.path().join("sub/b.txt"), "world").unwrap();
+    fs::write(dst.path().join("stale.txt"), "old").unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .arg("--delete")
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(dst.path().join("a.txt")).unwrap(), "hello");
+    assert_eq!(fs::read_to_string(dst.path().join("sub/b.txt")).unwrap(), "world");
+    assert!(!dst.path().join("stale.txt").exists());
+
+    // Directory mtimes are set once nothing more is written into them (not on Windows)
+    #[cfg(unix)]
+    {
+        fs::write(src.path().join("sub/c.txt"), "more").unwrap();
+        fs::File::open(src.path().join("sub")).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(1_500_000_000)).unwrap();
+        fs::write(dst.path().join("sub/stale.txt"), "old").unwrap();
+        fastsync().arg(src.path()).arg(dst.path()).arg("--delete").assert().success();
+        let mtime = fs::metadata(dst.path().join("sub")).unwrap().modified().unwrap();
+        assert_eq!(mtime, UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+    }
+}
+
+#[test]
+fn test_local_sync_delete_spares_excluded() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    fs::write(src.path().join("a.txt"), "hello").unwrap();
+    fs::write(src.path().join("skip.log"), "excluded").unwrap();
+    fs::write(dst.path().join("keep.log"), "destination only, excluded").unwrap();
+    fs::write(dst.path().join("stale.txt"), "old").unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete", "--exclude", "*.log"])
+        .assert()
+        .success();
+
+    assert!(dst.path().join("a.txt").exists());
+    assert!(!dst.path().join("skip.log").exists());
+    assert!(dst.path().join("keep.log").exists());
+    assert!(!dst.path().join("stale.txt").exists());
+}
+
+#[test]
+fn test_local_sync_delete_excluded() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    fs::write(src.path().join("a.txt"), "hello").unwrap();
+    fs::create_dir(dst.path().join("logs")).unwrap();
+    fs::write(dst.path().join("logs/old.log"), "excluded").unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete-excluded", "--exclude", "logs"])
+        .assert()
+        .success();
+
+    assert!(dst.path().join("a.txt").exists());
+    assert!(!dst.path().join("logs").exists());
+}
+
+#[test]
+fn test_local_sync_delete_limits() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    fs::write(src.path().join("a.txt"), "hello").unwrap();
+    for i in 0..3 {
+        fs::write(dst.path().join(format!("stale{}.txt", i)), "old").unwrap();
+    }
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete", "--max-delete", "2"])
+        .assert()
+        .code(25)
+        .stderr(predicates::str::contains("--max-delete 2"));
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete", "--max-delete-percent", "50"])
+        .assert()
+        .failure();
+    assert!(dst.path().join("stale0.txt").exists());
+
+    // An empty source never wipes the destination
+    let empty = tempfile::tempdir().unwrap();
+    fastsync()
+        .arg(empty.path())
+        .arg(dst.path())
+        .arg("--delete")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("is empty"));
+    assert!(dst.path().join("stale0.txt").exists());
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete", "--max-delete", "3"])
+        .assert()
+        .success();
+    assert!(!dst.path().join("stale0.txt").exists());
+}
+
+#[test]
+fn test_local_sync_block_level() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    let mut data: Vec<u8> = (0..50_000u32).map(|i| (i % 239) as u8).collect();
+    fs::write(dst.path().join("big.bin"), &data).unwrap();
+    set_mtime(&dst.path().join("big.bin"), 1_000_000);
+    data[25_000..25_004].copy_from_slice(b"edit");
+    fs::write(src.path().join("big.bin"), &data).unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .arg("--block-level")
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(dst.path().join("big.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_local_sync_checksum_mode() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    // Same size, destination looks newer: only --checksum notices the difference
+    fs::write(src.path().join("conf.txt"), "value=1").unwrap();
+    set_mtime(&src.path().join("conf.txt"), 1_000_000);
+    fs::write(dst.path().join("conf.txt"), "value=2").unwrap();
+
+    fastsync().arg(src.path()).arg(dst.path()).assert().success();
+    assert_eq!(fs::read_to_string(dst.path().join("conf.txt")).unwrap(), "value=2");
+
+    fastsync().arg(src.path()).arg(dst.path()).arg("--checksum").assert().success();
+    assert_eq!(fs::read_to_string(dst.path().join("conf.txt")).unwrap(), "value=1");
+}
+
+#[test]
+fn test_local_sync_backup() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    fs::write(src.path().join("a.txt"), "new").unwrap();
+    fs::write(dst.path().join("a.txt"), "old").unwrap();
+    set_mtime(&dst.path().join("a.txt"), 1_000_000_000);
+    fs::create_dir(dst.path().join("sub")).unwrap();
+    fs::write(dst.path().join("sub/stale.txt"), "stale").unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete", "--backup-dir", ".backup"])
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(dst.path().join("a.txt")).unwrap(), "new");
+    assert_eq!(fs::read_to_string(dst.path().join(".backup/a.txt")).unwrap(), "old");
+    assert!(!dst.path().join("sub").exists());
+    assert_eq!(fs::read_to_string(dst.path().join(".backup/sub/stale.txt")).unwrap(), "stale");
+
+    // Without --backup-dir, backups sit next to the file, deleted directories included
+    fs::write(src.path().join("a.txt"), "newer").unwrap();
+    set_mtime(&src.path().join("a.txt"), 2_000_000_000);
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete", "--backup", "--suffix", ".orig"])
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(dst.path().join("a.txt")).unwrap(), "newer");
+    assert_eq!(fs::read_to_string(dst.path().join("a.txt.orig")).unwrap(), "new");
+    assert!(!dst.path().join(".backup").exists());
+    assert!(dst.path().join(".backup.orig/a.txt").exists());
+
+    // The user's own files with the suffix are synced once, not on every run
+    fs::write(src.path().join("notes.orig"), "mine").unwrap();
+    for expected in [true, false] {
+        fastsync()
+            .arg(src.path())
+            .arg(dst.path())
+            .args(["--delete", "--backup", "--suffix", ".orig", "--itemize-changes"])
+            .assert()
+            .success()
+            .stdout(predicates::function::function(move |out: &str| out.contains("notes.orig") == expected));
+    }
+    assert_eq!(fs::read_to_string(dst.path().join("notes.orig")).unwrap(), "mine");
+    assert!(dst.path().join("a.txt.orig").exists());
+}
+
+#[test]
+fn test_local_sync_itemize_and_report_json() {
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let report_path = out.path().join("report.json");
+
+    fs::write(src.path().join("new.txt"), "hello").unwrap();
+    fs::write(src.path().join("grown.txt"), "longer").unwrap();
+    fs::write(dst.path().join("grown.txt"), "old").unwrap();
+    fs::write(dst.path().join("stale.txt"), "stale").unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--delete", "--itemize-changes", "--report-json"])
+        .arg(&report_path)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("new              new.txt"))
+        .stdout(predicates::str::contains("size             grown.txt"))
+        .stdout(predicates::str::contains("deleted          stale.txt"));
+
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report_path).unwrap()).unwrap();
+    assert_eq!(report["dry_run"], false);
+    assert_eq!(report["totals"]["transferred"], 2);
+    assert_eq!(report["totals"]["deleted"], 1);
+    assert_eq!(report["totals"]["failed"], 0);
+    assert_eq!(report["totals"]["literal_bytes"], 11);
+    assert!(report["error"].is_null());
+    let files = report["files"].as_array().unwrap();
+    assert_eq!(files.len(), 3);
+    assert!(files.iter().all(|f| f["done"] == true));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_local_sync_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    fs::create_dir(src.path().join("v1.2.3")).unwrap();
+    fs::write(src.path().join("v1.2.3/app.bin"), "app").unwrap();
+    symlink("v1.2.3", src.path().join("current")).unwrap();
+    symlink("/etc/hostname", src.path().join("outside")).unwrap();
+    // An old release dir where the link now is must not keep receiving files
+    fs::create_dir(dst.path().join("v0")).unwrap();
+    symlink("v0", dst.path().join("current")).unwrap();
+    symlink("v0", dst.path().join("latest")).unwrap();
+    fs::create_dir(src.path().join("latest")).unwrap();
+    fs::write(src.path().join("latest/notes.txt"), "notes").unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .arg("--safe-links")
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_link(dst.path().join("current")).unwrap(), Path::new("v1.2.3"));
+    assert_eq!(fs::read_to_string(dst.path().join("current/app.bin")).unwrap(), "app");
+    assert!(fs::symlink_metadata(dst.path().join("outside")).is_err());
+    assert!(fs::symlink_metadata(dst.path().join("latest")).unwrap().is_dir());
+    assert_eq!(fs::read_to_string(dst.path().join("latest/notes.txt")).unwrap(), "notes");
+    assert!(!dst.path().join("v0/notes.txt").exists());
+
+    // --copy-links turns links into what they point to
+    let copied = tempfile::tempdir().unwrap();
+    fastsync()
+        .arg(src.path())
+        .arg(copied.path())
+        .args(["--copy-links", "--safe-links"])
+        .assert()
+        .success();
+    assert!(fs::symlink_metadata(copied.path().join("current")).unwrap().is_dir());
+    assert_eq!(fs::read_to_string(copied.path().join("current/app.bin")).unwrap(), "app");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_local_sync_hard_links() {
+    use std::os::unix::fs::MetadataExt;
+
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+
+    fs::create_dir(src.path().join("b")).unwrap();
+    fs::write(src.path().join("a.bin"), "shared").unwrap();
+    fs::hard_link(src.path().join("a.bin"), src.path().join("b/c.bin")).unwrap();
+    fs::hard_link(src.path().join("a.bin"), src.path().join("d.bin")).unwrap();
+    // A separate copy already in place gets relinked
+    fs::write(dst.path().join("d.bin"), "shared").unwrap();
+
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .arg("-H")
+        .assert()
+        .success();
+
+    let ino = |path: &str| fs::metadata(dst.path().join(path)).unwrap().ino();
+    assert_eq!(ino("a.bin"), ino("b/c.bin"));
+    assert_eq!(ino("a.bin"), ino("d.bin"));
+    assert_eq!(fs::read_to_string(dst.path().join("b/c.bin")).unwrap(), "shared");
+
+    // Without -H every name is a copy of its own
+    let copied = tempfile::tempdir().unwrap();
+    fastsync().arg(src.path()).arg(copied.path()).assert().success();
+    assert_ne!(
+        fs::metadata(copied.path().join("a.bin")).unwrap().ino(),
+        fs::metadata(copied.path().join("d.bin")).unwrap().ino()
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_local_sync_xattrs() {
+    use fastsync::util::xattr::{self, XattrScope, Xattrs};
+
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    let scope = XattrScope { xattrs: true, acls: false };
+    let labeled = |label: &str| Xattrs::from([("user.label".to_string(), label.as_bytes().to_vec())]);
+
+    fs::write(src.path().join("a.txt"), "data").unwrap();
+    if xattr::write(&src.path().join("a.txt"), scope, &labeled("v1")).is_err() {
+        // No user xattrs on this filesystem
+        return;
+    }
+
+    fastsync().arg(src.path()).arg(dst.path()).arg("--xattrs").assert().success();
+    assert_eq!(xattr::read(&dst.path().join("a.txt"), scope).unwrap(), labeled("v1"));
+
+    // Only the label changes: updated in place, without another copy
+    xattr::write(&src.path().join("a.txt"), scope, &labeled("v2")).unwrap();
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .args(["--xattrs", "--itemize-changes"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("xattrs           a.txt"));
+    assert_eq!(xattr::read(&dst.path().join("a.txt"), scope).unwrap(), labeled("v2"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_local_sync_mode_only_change() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    fs::write(src.path().join("secret"), "token").unwrap();
+    fastsync().arg(src.path()).arg(dst.path()).assert().success();
+
+    // Same content and mtime; only the mode changes
+    fs::set_permissions(src.path().join("secret"), fs::Permissions::from_mode(0o600)).unwrap();
+    fastsync()
+        .arg(src.path())
+        .arg(dst.path())
+        .arg("--itemize-changes")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("perms            secret"));
+    let mode = fs::metadata(dst.path().join("secret")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_local_sync_read_only_dir_and_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let src = tempfile::tempdir().unwrap();
+    let dst = tempfile::tempdir().unwrap();
+    fs::create_dir_all(src.path().join("ro/sub")).unwrap();
+    fs::write(src.path().join("ro/sub/file"), "data").unwrap();
+    fs::set_permissions(src.path().join("ro/sub/file"), fs::Permissions::from_mode(0o444)).unwrap();
+    fs::set_permissions(src.path().join("ro/sub"), fs::Permissions::from_mode(0o555)).unwrap();
+    fs::set_permissions(src.path().join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+
+    fastsync().arg(src.path()).arg(dst.path()).assert().success();
+    assert_eq!(fs::read_to_string(dst.path().join("ro/sub/file")).unwrap(), "data");
+    for (path, expected) in [("ro", 0o555), ("ro/sub", 0o555), ("ro/sub/file", 0o444)] {
+        let mode = fs::metadata(dst.path().join(path)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, expected, "{}", path);
+    }
+
+    // Later runs write into and delete from the read-only directories, which are
+    // only opened up while they do. A new mtime on the read-only file is set
+    // without opening it for writing.
+    let file = src.path().join("ro/sub/file");
+    for dir in [src.path(), dst.path()] {
+        fs::set_permissions(dir.join("ro"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    fs::write(src.path().join("ro/new"), "new").unwrap();
+    fs::write(dst.path().join("ro/stale"), "old").unwrap();
+    fs::set_permissions(src.path().join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+    fs::set_permissions(dst.path().join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+    fs::set_permissions(src.path().join("ro/sub"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+    set_mtime(&file, 4_000_000_000);
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o444)).unwrap();
+    fs::set_permissions(src.path().join("ro/sub"), fs::Permissions::from_mode(0o555)).unwrap();
+    fastsync().arg(src.path()).arg(dst.path()).arg("--delete").assert().success();
+    let mtime = fs::metadata(dst.path().join("ro/sub/file")).unwrap().modified().unwrap();
+    assert_eq!(mtime, UNIX_EPOCH + Duration::from_secs(4_000_000_000));
+    assert_eq!(fs::read_to_string(dst.path().join("ro/new")).unwrap(), "new");
+    assert!(!dst.path().join("ro/stale").exists());
+    for path in ["ro", "ro/sub"] {
+        let mode = fs::metadata(dst.path().join(path)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o555, "{}", path);
+    }
+
+    // Let the temp dirs be removed
+    for dir in [src.path(), dst.path()] {
+        for path in ["ro", "ro/sub"] {
+            fs::set_permissions(dir.join(path), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+}
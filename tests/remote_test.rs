@@ -1,14 +1,20 @@
 use fastsync::transport::Transport;
-use fastsync::remote::agentless::AgentlessRemote;
-use fastsync::scanner::{Scanner, FileEntry};
+use fastsync::apply::backup::Backup;
+use fastsync::apply::metadata::Metadata;
+use fastsync::remote::agentless::{backup_paths, delete_paths, verify_checksum, AgentlessRemote};
+use fastsync::FastSyncError;
+use fastsync::scanner::{Scanner, FileEntry, FileKind};
 use fastsync::Result;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 struct MockTransport {
     exec_responses: Mutex<Vec<(String, String)>>, 
     dir_entries: Mutex<HashMap<PathBuf, Vec<FileEntry>>>,
+    executed: Mutex<Vec<String>>,
+    // Paths a rename can't replace until a command removes them
+    occupied: Mutex<HashSet<PathBuf>>,
 }
 
 impl MockTransport {
@@ -16,6 +22,8 @@ impl MockTransport {
         Self { 
             exec_responses: Mutex::new(Vec::new()),
             dir_entries: Mutex::new(HashMap::new()),
+            executed: Mutex::new(Vec::new()),
+            occupied: Mutex::new(HashSet::new()),
         }
     }
     
@@ -30,6 +38,8 @@ impl MockTransport {
 
 impl Transport for MockTransport {
     fn exec(&self, command: &str) -> Result<String> {
+        self.executed.lock().unwrap().push(command.to_string());
+        self.occupied.lock().unwrap().retain(|p| !command.contains(&*p.to_string_lossy()));
         let responses = self.exec_responses.lock().unwrap();
         if let Some(pos) = responses.iter().position(|(c, _)| command.contains(c)) {
              return Ok(responses[pos].1.clone());
@@ -42,6 +52,10 @@ impl Transport for MockTransport {
         Ok(())
     }
     
+    fn download_file(&self, _remote: &Path, _local: &Path) -> Result<()> {
+        Ok(())
+    }
+    
     fn list_dir(&self, path: &Path) -> Result<Vec<FileEntry>> {
         let entries = self.dir_entries.lock().unwrap();
         Ok(entries.get(path).cloned().unwrap_or_default())
@@ -50,6 +64,24 @@ impl Transport for MockTransport {
     fn create_dir_all(&self, _path: &Path) -> Result<()> {
         Ok(())
     }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.occupied.lock().unwrap().contains(to) {
+            return Err(FastSyncError::RemoteCommand(format!("{} exists", to.display())));
+        }
+        self.executed.lock().unwrap().push(format!("rename {} {}", from.display(), to.display()));
+        Ok(())
+    }
+
+    fn set_metadata(&self, path: &Path, metadata: &Metadata) -> Result<()> {
+        self.executed.lock().unwrap().push(format!("setstat {} {:o}", path.display(), metadata.mode));
+        Ok(())
+    }
+
+    fn symlink(&self, target: &str, path: &Path) -> Result<()> {
+        self.executed.lock().unwrap().push(format!("symlink {} {}", path.display(), target));
+        Ok(())
+    }
 }
 
 #[test]
@@ -65,15 +97,15 @@ fn test_remote_scan_recursive() {
     
     // Entries in /remote
     transport.add_dir_entry(root, FileEntry {
-        path: "file.txt".into(), size: 100, mtime: 1000, mode: 0o644, is_dir: false 
+        path: "file.txt".into(), size: 100, mtime: 1000, mode: 0o644, ..Default::default()
     });
     transport.add_dir_entry(root, FileEntry {
-        path: "subdir".into(), size: 0, mtime: 1000, mode: 0o755, is_dir: true
+        path: "subdir".into(), size: 0, mtime: 1000, mode: 0o755, kind: FileKind::Dir, ..Default::default()
     });
     
     // Entries in /remote/subdir
     transport.add_dir_entry(&root.join("subdir"), FileEntry {
-        path: "deep.txt".into(), size: 50, mtime: 1000, mode: 0o644, is_dir: false
+        path: "deep.txt".into(), size: 50, mtime: 1000, mode: 0o644, ..Default::default()
     });
     
     let mut remote = AgentlessRemote::new(&transport);
@@ -85,8 +117,77 @@ fn test_remote_scan_recursive() {
     assert_eq!(file.size, 100);
     
     let subdir = manifest.entries.iter().find(|e| e.path == "subdir").unwrap();
-    assert!(subdir.is_dir);
+    assert!(subdir.is_dir());
     
     let deep = manifest.entries.iter().find(|e| e.path == "subdir/deep.txt").unwrap();
     assert_eq!(deep.size, 50);
 }
+
+#[test]
+fn test_agentless_checksum_verification() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = dir.path().join("file.txt");
+    std::fs::write(&local, b"hello").unwrap();
+    let b3 = blake3::hash(b"hello").to_hex().to_string();
+
+    let transport = MockTransport::new();
+    transport.add_response("b3sum", &format!("b3 {}\n", b3));
+    verify_checksum(&transport, &local, "/remote/file.txt", false).expect("checksum should match");
+
+    let transport = MockTransport::new();
+    transport.add_response("b3sum", &format!("b3 {}\n", blake3::hash(b"corrupt").to_hex()));
+    let err = verify_checksum(&transport, &local, "/remote/file.txt", false).unwrap_err();
+    assert!(matches!(err, FastSyncError::ChecksumMismatch { .. }));
+
+    // sha256sum prints "<hex>  <path>"
+    let transport = MockTransport::new();
+    transport.add_response(
+        "b3sum",
+        "sha256 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824 /remote/file.txt\n",
+    );
+    verify_checksum(&transport, &local, "/remote/file.txt", false).expect("sha256 should match");
+
+    // No tool on the remote: verification is skipped
+    let transport = MockTransport::new();
+    transport.add_response("b3sum", "none\n");
+    verify_checksum(&transport, &local, "/remote/file.txt", false).expect("no tool means no check");
+}
+
+#[test]
+fn test_agentless_delete_is_batched() {
+    let transport = MockTransport::new();
+    let paths = vec!["/srv/app/old dir".to_string(), "/srv/app/it's.txt".to_string()];
+    delete_paths(&transport, &paths, false).unwrap();
+    delete_paths(&transport, &["D:/www/old".to_string(), "D:/www/a'b".to_string()], true).unwrap();
+
+    let executed = std::mem::take(&mut *transport.executed.lock().unwrap());
+    assert_eq!(executed.len(), 2);
+    assert_eq!(executed[0], "rm -rf -- '/srv/app/old dir' '/srv/app/it'\\''s.txt'");
+    assert!(executed[1].contains("-LiteralPath 'D:/www/old','D:/www/a''b'\""));
+
+    // Long lists are split so each command stays under the shell's limit
+    let many: Vec<String> = (0..1000).map(|i| format!("/srv/app/some/fairly/long/path/file{:04}.txt", i)).collect();
+    delete_paths(&transport, &many, false).unwrap();
+    let executed = transport.executed.lock().unwrap();
+    assert!(executed.len() > 1);
+    assert!(executed.iter().all(|cmd| cmd.len() <= 8000));
+    assert_eq!(executed.iter().map(|cmd| cmd.matches("file").count()).sum::<usize>(), 1000);
+}
+
+#[test]
+fn test_agentless_backup_renames() {
+    let transport = MockTransport::new();
+    let backup = Backup { root: PathBuf::from("/srv/app"), dir: Some(PathBuf::from("/srv/backup")), suffix: String::new() };
+    backup_paths(&transport, &backup, &[PathBuf::from("/srv/app/conf/app.toml")]).unwrap();
+
+    let executed = transport.executed.lock().unwrap();
+    assert_eq!(executed.as_slice(), ["rename /srv/app/conf/app.toml /srv/backup/conf/app.toml"]);
+    drop(executed);
+
+    // An old backup that is a directory is removed before it is replaced
+    transport.executed.lock().unwrap().clear();
+    transport.occupied.lock().unwrap().insert(PathBuf::from("/srv/backup/conf"));
+    backup_paths(&transport, &backup, &[PathBuf::from("/srv/app/conf")]).unwrap();
+    let executed = transport.executed.lock().unwrap();
+    assert_eq!(executed.as_slice(), ["rm -rf -- '/srv/backup/conf'", "rename /srv/app/conf /srv/backup/conf"]);
+}
@@ -0,0 +1,504 @@
+use fastsync::apply::backup::Backup;
+use fastsync::apply::metadata::Metadata;
+use fastsync::apply::partial::Partial;
+use fastsync::delta::block_level::{compute_signature, DeltaOp, DeltaStream};
+use fastsync::protocol::{read_frame, write_frame, BatchFile, Capabilities, FrameCodec, Hello, Request, Response, PROTOCOL_VERSION};
+use fastsync::scanner::ScanOptions;
+use fastsync::server::Server;
+use std::fs;
+use std::io::Cursor;
+
+/// Frames of the handshake
+const PLAIN: FrameCodec = FrameCodec { compress_level: None, version: None };
+/// Frames after an uncompressed handshake at the current protocol version
+const SESSION: FrameCodec = FrameCodec { compress_level: None, version: Some(PROTOCOL_VERSION) };
+
+/// Run `requests` through a server after a plain handshake and decode every response.
+/// Requests are all sent up front (pipelined), with IDs 100, 101, ...
+fn exchange(requests: Vec<Request>) -> Vec<Response> {
+    let mut input = Vec::new();
+    write_frame(&mut input, PLAIN, 0, &Request::Hello(Hello::new(Capabilities::supported(), None))).unwrap();
+    for (id, req) in (100..).zip(&requests) {
+        write_frame(&mut input, SESSION, id, req).unwrap();
+    }
+
+    let mut output = Vec::new();
+    Server::new().serve(Cursor::new(input), &mut output).unwrap();
+
+    let mut reader = output.as_slice();
+    assert!(matches!(read_frame(&mut reader, PLAIN).unwrap(), Some((_, Response::Hello(_)))));
+    let mut responses = Vec::new();
+    while let Some((id, resp)) = read_frame(&mut reader, SESSION).unwrap() {
+        assert_eq!(id, 100 + responses.len() as u32, "responses come back in order with their IDs");
+        responses.push(resp);
+    }
+    assert_eq!(responses.len(), requests.len());
+    responses
+}
+
+/// Metadata with just an mtime and mode
+fn metadata(mtime: i64, mode: u32) -> Metadata {
+    Metadata { mtime, mode, ..Default::default() }
+}
+
+/// Non-repeating pseudo-random bytes, so blocks don't match by accident.
+fn sample(len: u32, seed: u32) -> Vec<u8> {
+    (0..len).map(|i| (i.wrapping_add(seed).wrapping_mul(2_654_435_761) >> 13) as u8).collect()
+}
+
+#[test]
+fn test_streamed_apply_delta() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("data.bin");
+    let old_data = sample(100_000, 17);
+    fs::write(&target, &old_data).unwrap();
+
+    let mut new_data = old_data[..40_000].to_vec();
+    new_data.extend(sample(5_000, 3));
+    new_data.extend_from_slice(&old_data[40_000..]);
+
+    let block_size = 1024;
+    let sig = compute_signature(&mut Cursor::new(&old_data), block_size).unwrap();
+    let mut stream = DeltaStream::new(Cursor::new(&new_data), sig);
+
+    let path = target.to_string_lossy().to_string();
+    let mut requests = vec![Request::BeginDelta { path, block_size }];
+    loop {
+        let ops = stream.next_chunk(8 * 1024).unwrap();
+        if ops.is_empty() {
+            break;
+        }
+        requests.push(Request::DeltaChunk { ops });
+    }
+    requests.push(Request::CommitDelta { final_size: stream.bytes_read(), checksum: stream.checksum() });
+
+    let responses = exchange(requests);
+    assert!(responses.iter().all(|r| matches!(r, Response::Ok)), "{:?}", responses);
+    assert_eq!(fs::read(&target).unwrap(), new_data);
+}
+
+#[test]
+fn test_commit_rejects_wrong_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("data.bin");
+    fs::write(&target, b"original").unwrap();
+
+    let path = target.to_string_lossy().to_string();
+    let responses = exchange(vec![
+        Request::BeginDelta { path, block_size: 4 },
+        Request::DeltaChunk { ops: vec![DeltaOp::Data { data: b"short".to_vec() }] },
+        Request::CommitDelta { final_size: 100, checksum: *blake3::hash(b"short").as_bytes() },
+    ]);
+
+    assert!(matches!(responses[2], Response::Error { .. }));
+    assert_eq!(fs::read(&target).unwrap(), b"original");
+}
+
+#[test]
+fn test_commit_rejects_wrong_checksum() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("data.bin");
+    fs::write(&target, b"original").unwrap();
+
+    let path = target.to_string_lossy().to_string();
+    let responses = exchange(vec![
+        Request::BeginDelta { path, block_size: 4 },
+        Request::DeltaChunk { ops: vec![DeltaOp::Data { data: b"new content".to_vec() }] },
+        Request::CommitDelta { final_size: 11, checksum: *blake3::hash(b"other content").as_bytes() },
+    ]);
+
+    assert!(matches!(responses[2], Response::ChecksumMismatch { .. }));
+    assert_eq!(fs::read(&target).unwrap(), b"original");
+    assert!(fs::read_dir(dir.path()).unwrap().count() == 1, "temp file left behind");
+}
+
+#[test]
+fn test_streamed_get_delta() {
+    let dir = tempfile::tempdir().unwrap();
+    let remote = dir.path().join("remote.bin");
+    let remote_data = sample(600_000, 29);
+    fs::write(&remote, &remote_data).unwrap();
+
+    // Local copy has the first half only
+    let local_data = remote_data[..300_000].to_vec();
+    let sig = compute_signature(&mut Cursor::new(&local_data), 4096).unwrap();
+
+    let path = remote.to_string_lossy().to_string();
+    let mut requests = vec![Request::GetDelta { path, signature: sig }];
+    requests.extend((0..8).map(|_| Request::NextDeltaChunk));
+    let responses = exchange(requests);
+
+    let mut ops = Vec::new();
+    let mut chunks = 0;
+    let mut responses = responses.into_iter();
+    for resp in responses.by_ref() {
+        match resp {
+            Response::DeltaChunk { ops: chunk, checksum } => {
+                chunks += 1;
+                ops.extend(chunk);
+                if let Some(checksum) = checksum {
+                    assert_eq!(checksum, *blake3::hash(&remote_data).as_bytes());
+                    break;
+                }
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+    assert!(chunks > 1);
+    // Chunks asked for past the end just get an explicit end of stream
+    assert!(responses.all(|r| matches!(r, Response::Ok)));
+
+    let mut out = Vec::new();
+    fastsync::delta::block_level::apply_ops(&mut Cursor::new(&local_data), &ops, &mut out, 4096).unwrap();
+    assert_eq!(out, remote_data);
+}
+
+#[test]
+fn test_compressed_session() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "hello").unwrap();
+
+    // Hello goes out plain; everything after it is compressed
+    let mut input = Vec::new();
+    let compressed = FrameCodec { compress_level: Some(3), ..SESSION };
+    write_frame(&mut input, PLAIN, 0, &Request::Hello(Hello::new(Capabilities::supported(), Some(3)))).unwrap();
+    let path = dir.path().to_string_lossy().to_string();
+    write_frame(&mut input, compressed, 1, &Request::GetManifest { path, options: ScanOptions::default() }).unwrap();
+
+    let mut output = Vec::new();
+    Server::new().serve(Cursor::new(input), &mut output).unwrap();
+
+    let mut reader = output.as_slice();
+    match read_frame(&mut reader, PLAIN).unwrap() {
+        Some((_, Response::Hello(hello))) => assert!(hello.capabilities.contains(Capabilities::COMPRESSION)),
+        other => panic!("unexpected response: {:?}", other),
+    }
+    match read_frame(&mut reader, compressed).unwrap() {
+        Some((1, Response::Manifest(manifest))) => {
+            assert!(manifest.entries.iter().any(|e| e.path == "a.txt"));
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[test]
+fn test_manifest_honors_excludes() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "keep").unwrap();
+    fs::write(dir.path().join("debug.log"), "skip").unwrap();
+    fs::create_dir(dir.path().join("cache")).unwrap();
+    fs::write(dir.path().join("cache/blob"), "skip").unwrap();
+
+    let options = ScanOptions { excludes: vec!["*.log".into(), "cache".into()], ..Default::default() };
+    let responses = exchange(vec![Request::GetManifest { path: dir.path().to_string_lossy().to_string(), options }]);
+    match &responses[0] {
+        Response::Manifest(manifest) => {
+            let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+            assert_eq!(paths, vec!["a.txt"]);
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[test]
+fn test_previous_protocol_still_served() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "data").unwrap();
+
+    // A client one version behind: the session settles on its version and layout
+    let hello = Hello { max_version: PROTOCOL_VERSION - 1, ..Hello::new(Capabilities::supported(), None) };
+    let previous = FrameCodec { version: Some(PROTOCOL_VERSION - 1), ..SESSION };
+    let mut input = Vec::new();
+    write_frame(&mut input, PLAIN, 0, &Request::Hello(hello)).unwrap();
+    let options = ScanOptions::default();
+    write_frame(&mut input, previous, 1, &Request::GetManifest { path: dir.path().to_string_lossy().to_string(), options }).unwrap();
+
+    let mut output = Vec::new();
+    Server::new().serve(Cursor::new(input), &mut output).unwrap();
+    let mut reader = output.as_slice();
+    assert!(matches!(read_frame(&mut reader, PLAIN).unwrap(), Some((_, Response::Hello(_)))));
+    match read_frame(&mut reader, previous).unwrap() {
+        Some((1, Response::Manifest(manifest))) => assert_eq!(manifest.entries[0].path, "a.txt"),
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[test]
+fn test_handshake_required() {
+    let mut input = Vec::new();
+    write_frame(&mut input, PLAIN, 0, &Request::NextDeltaChunk).unwrap();
+    let mut output = Vec::new();
+    Server::new().serve(Cursor::new(input), &mut output).unwrap();
+    match read_frame(&mut output.as_slice(), PLAIN).unwrap() {
+        Some((_, Response::Error { message })) => assert!(message.contains("Handshake required")),
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[test]
+fn test_incompatible_version_refused() {
+    let hello = Hello { min_version: 99, max_version: 100, ..Hello::new(Capabilities::supported(), None) };
+    let mut input = Vec::new();
+    write_frame(&mut input, PLAIN, 0, &Request::Hello(hello)).unwrap();
+    write_frame(&mut input, PLAIN, 0, &Request::NextDeltaChunk).unwrap();
+
+    let mut output = Vec::new();
+    let result = Server::new().serve(Cursor::new(input), &mut output);
+    assert!(matches!(result, Err(fastsync::FastSyncError::Protocol(_))));
+
+    // The agent still answers with its own range so the client can explain the failure
+    let mut reader = output.as_slice();
+    assert!(matches!(read_frame(&mut reader, PLAIN).unwrap(), Some((_, Response::Hello(_)))));
+    assert!(read_frame::<_, Response>(&mut reader, PLAIN).unwrap().is_none());
+}
+
+#[test]
+fn test_batch_writes_whole_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = |name: &str, data: &[u8], checksum| BatchFile {
+        path: dir.path().join(name).to_string_lossy().to_string(),
+        data: data.to_vec(),
+        metadata: metadata(1_600_000_000, 0),
+        checksum,
+    };
+    let hash = |data: &[u8]| *blake3::hash(data).as_bytes();
+
+    let responses = exchange(vec![
+        Request::Batch { files: vec![
+            file("a.txt", b"alpha", hash(b"alpha")),
+            file("sub/b.txt", b"beta", hash(b"beta")),
+        ] },
+        Request::Batch { files: vec![file("bad.txt", b"corrupted", hash(b"original"))] },
+    ]);
+
+    match &responses[0] {
+        Response::Batch { errors } => assert_eq!(errors, &vec![None, None]),
+        other => panic!("unexpected response: {:?}", other),
+    }
+    match &responses[1] {
+        Response::Batch { errors } => assert!(errors[0].as_deref().unwrap().contains("Checksum mismatch")),
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), b"alpha");
+    assert_eq!(fs::read(dir.path().join("sub/b.txt")).unwrap(), b"beta");
+    let mtime = fs::metadata(dir.path().join("a.txt")).unwrap().modified().unwrap();
+    assert_eq!(mtime.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(), 1_600_000_000);
+    assert!(!dir.path().join("bad.txt").exists());
+}
+
+#[test]
+fn test_backup_before_replace_and_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "old a").unwrap();
+    fs::write(dir.path().join("gone.txt"), "old gone").unwrap();
+    let data = b"new a".to_vec();
+    let checksum = *blake3::hash(&data).as_bytes();
+
+    let responses = exchange(vec![
+        Request::SetBackup { backup: Some(Backup { root: dir.path().to_path_buf(), dir: Some(".bak".into()), suffix: String::new() }) },
+        Request::Batch { files: vec![BatchFile {
+            path: dir.path().join("a.txt").to_string_lossy().to_string(),
+            data,
+            metadata: metadata(1_600_000_000, 0),
+            checksum,
+        }] },
+        Request::Delete { path: dir.path().join("gone.txt").to_string_lossy().to_string() },
+    ]);
+
+    assert!(matches!(responses[0], Response::Ok));
+    assert!(matches!(&responses[1], Response::Batch { errors } if errors == &vec![None]));
+    assert!(matches!(responses[2], Response::Ok));
+    assert_eq!(fs::read_to_string(dir.path().join("a.txt")).unwrap(), "new a");
+    assert_eq!(fs::read_to_string(dir.path().join(".bak/a.txt")).unwrap(), "old a");
+    assert!(!dir.path().join("gone.txt").exists());
+    assert_eq!(fs::read_to_string(dir.path().join(".bak/gone.txt")).unwrap(), "old gone");
+}
+
+#[test]
+fn test_interrupted_delta_resumes_from_partial() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("big.bin");
+    let path = target.to_string_lossy().to_string();
+    let partial = Partial { dir: Some(".partial".into()) };
+    let new_data = sample(50_000, 5);
+    let block_size = 1024;
+
+    // The connection drops after the first 20 KB arrived
+    let responses = exchange(vec![
+        Request::SetPartial { partial: Some(partial.clone()) },
+        Request::BeginDelta { path: path.clone(), block_size },
+        Request::DeltaChunk { ops: vec![DeltaOp::Data { data: new_data[..20_000].to_vec() }] },
+    ]);
+    assert!(responses.iter().all(|r| matches!(r, Response::Ok)), "{:?}", responses);
+    assert!(!target.exists());
+    assert_eq!(fs::read(dir.path().join(".partial/big.bin")).unwrap(), &new_data[..20_000]);
+
+    // The next run gets the partial file's signature and only sends the rest
+    let responses = exchange(vec![
+        Request::SetPartial { partial: Some(partial.clone()) },
+        Request::GetSignature { path: path.clone(), block_size },
+    ]);
+    let Response::Signature(sig) = &responses[1] else { panic!("unexpected response: {:?}", responses[1]) };
+    assert_eq!(sig.file_size, 20_000);
+
+    let mut stream = DeltaStream::new(Cursor::new(&new_data), sig.clone());
+    let mut requests = vec![
+        Request::SetPartial { partial: Some(partial) },
+        Request::BeginDelta { path, block_size },
+    ];
+    let mut literal = 0;
+    loop {
+        let ops = stream.next_chunk(8 * 1024).unwrap();
+        if ops.is_empty() {
+            break;
+        }
+        literal += ops.iter().map(|op| match op { DeltaOp::Data { data } => data.len(), _ => 0 }).sum::<usize>();
+        requests.push(Request::DeltaChunk { ops });
+    }
+    requests.push(Request::CommitDelta { final_size: stream.bytes_read(), checksum: stream.checksum() });
+    assert!(literal <= 30_000 + block_size, "sent {} literal bytes", literal);
+
+    let responses = exchange(requests);
+    assert!(responses.iter().all(|r| matches!(r, Response::Ok)), "{:?}", responses);
+    assert_eq!(fs::read(&target).unwrap(), new_data);
+    assert!(!dir.path().join(".partial").exists());
+}
+
+#[test]
+fn test_checksum_mismatch_keeps_partial() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big.bin").to_string_lossy().to_string();
+    let partial = Partial { dir: None };
+    let partial_path = dir.path().join("big.bin.partial.rrsync");
+    fs::write(&partial_path, b"first half").unwrap();
+
+    let responses = exchange(vec![
+        Request::SetPartial { partial: Some(partial) },
+        Request::BeginDelta { path, block_size: 4 },
+        Request::DeltaChunk { ops: vec![DeltaOp::Data { data: b"whole file".to_vec() }] },
+        Request::CommitDelta { final_size: 10, checksum: *blake3::hash(b"changed meanwhile").as_bytes() },
+    ]);
+
+    assert!(matches!(responses[3], Response::ChecksumMismatch { .. }));
+    assert_eq!(fs::read(&partial_path).unwrap(), b"first half");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_set_metadata_applies_mtime_and_mode() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("run.sh");
+    fs::write(&file, "#!/bin/sh").unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+
+    let responses = exchange(vec![
+        Request::SetMetadata {
+            path: file.to_string_lossy().to_string(),
+            metadata: Metadata { mtime_nsec: 250_000_000, ..metadata(1_600_000_000, 0o100750) },
+        },
+        Request::SetMetadata { path: dir.path().join("sub").to_string_lossy().to_string(), metadata: metadata(0, 0o40700) },
+        Request::SetMetadata { path: dir.path().join("missing").to_string_lossy().to_string(), metadata: metadata(0, 0o644) },
+    ]);
+
+    assert!(matches!(responses[0], Response::Ok));
+    assert!(matches!(responses[1], Response::Ok));
+    assert!(matches!(responses[2], Response::Error { .. }));
+    let applied = fs::metadata(&file).unwrap();
+    assert_eq!((applied.mtime(), applied.mtime_nsec()), (1_600_000_000, 250_000_000));
+    assert_eq!(applied.mode() & 0o7777, 0o750);
+    assert_eq!(fs::metadata(dir.path().join("sub")).unwrap().mode() & 0o7777, 0o700);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_metadata_waits_for_commit() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (good, bad) = (dir.path().join("good.bin"), dir.path().join("bad.bin"));
+    fs::write(&good, b"old").unwrap();
+    fs::write(&bad, b"old").unwrap();
+    let before = fs::metadata(&bad).unwrap().mtime();
+
+    let delta = |path: &std::path::Path, checksum: &[u8]| {
+        let path = path.to_string_lossy().to_string();
+        vec![
+            Request::BeginDelta { path: path.clone(), block_size: 4 },
+            Request::SetMetadata { path, metadata: metadata(1_500_000_000, 0o100600) },
+            Request::DeltaChunk { ops: vec![DeltaOp::Data { data: b"new".to_vec() }] },
+            Request::CommitDelta { final_size: 3, checksum: *blake3::hash(checksum).as_bytes() },
+        ]
+    };
+    let mut requests = delta(&good, b"new");
+    requests.extend(delta(&bad, b"other"));
+    let responses = exchange(requests);
+
+    assert!(responses[..4].iter().all(|r| matches!(r, Response::Ok)), "{:?}", responses);
+    let applied = fs::metadata(&good).unwrap();
+    assert_eq!((applied.mtime(), applied.mode() & 0o7777), (1_500_000_000, 0o600));
+    // A rejected commit leaves the old file's metadata alone
+    assert!(matches!(responses[7], Response::ChecksumMismatch { .. }));
+    assert_eq!(fs::metadata(&bad).unwrap().mtime(), before);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlink_replaces_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("current"), "a plain file").unwrap();
+    let link = |name: &str, target: &str| Request::Symlink {
+        path: dir.path().join(name).to_string_lossy().to_string(),
+        target: target.into(),
+    };
+
+    let responses = exchange(vec![link("current", "v2"), link("sub/new", "../v2")]);
+
+    assert!(responses.iter().all(|r| matches!(r, Response::Ok)));
+    assert_eq!(fs::read_link(dir.path().join("current")).unwrap(), std::path::Path::new("v2"));
+    assert_eq!(fs::read_link(dir.path().join("sub/new")).unwrap(), std::path::Path::new("../v2"));
+}
+
+#[test]
+fn test_block_size_bounds() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("data.bin");
+    fs::write(&target, sample(1_000_000, 5)).unwrap();
+
+    let path = target.to_string_lossy().to_string();
+    let responses = exchange(vec![
+        Request::GetSignature { path: path.clone(), block_size: 0 },
+        Request::GetSignature { path: path.clone(), block_size: usize::MAX },
+        Request::BeginDelta { path: path.clone(), block_size: 0 },
+        Request::BeginDelta { path, block_size: 1 << 30 },
+    ]);
+
+    assert!(matches!(responses[0], Response::Error { .. }));
+    match &responses[1] {
+        Response::Signature(sig) => assert_eq!(sig.block_size, fastsync::delta::block_level::MAX_BLOCK_SIZE),
+        other => panic!("unexpected response {:?}", other),
+    }
+    assert!(matches!(responses[2], Response::Error { .. }));
+    assert!(matches!(responses[3], Response::Error { .. }));
+}
+
+#[test]
+fn test_signature_of_missing_file_is_empty() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("new.bin").to_string_lossy().to_string();
+    let not_a_file = dir.path().to_string_lossy().to_string();
+
+    let responses = exchange(vec![
+        Request::GetSignature { path: missing, block_size: 1024 },
+        Request::GetSignature { path: not_a_file, block_size: 1024 },
+    ]);
+
+    match &responses[0] {
+        Response::Signature(sig) => assert!(sig.blocks.is_empty() && sig.file_size == 0),
+        other => panic!("unexpected response {:?}", other),
+    }
+    // Anything but a missing file is still an error
+    assert!(matches!(responses[1], Response::Error { .. }));
+}